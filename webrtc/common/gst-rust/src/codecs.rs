@@ -0,0 +1,486 @@
+// Audio and video codecs that can be sent with webrtcbin and their negotiation
+
+use structopt::StructOpt;
+
+use anyhow::bail;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    Vp8,
+    Vp9,
+    H264,
+    Av1,
+    Opus,
+    G722,
+    Pcmu,
+    Pcma,
+}
+
+impl Codec {
+    pub const VIDEO: [Codec; 4] = [Codec::Vp8, Codec::Vp9, Codec::H264, Codec::Av1];
+    pub const AUDIO: [Codec; 4] = [Codec::Opus, Codec::G722, Codec::Pcmu, Codec::Pcma];
+
+    // Media type as used in the SDP and the RTP caps
+    pub fn media(self) -> &'static str {
+        match self {
+            Codec::Vp8 | Codec::Vp9 | Codec::H264 | Codec::Av1 => "video",
+            Codec::Opus | Codec::G722 | Codec::Pcmu | Codec::Pcma => "audio",
+        }
+    }
+
+    pub fn encoding_name(self) -> &'static str {
+        match self {
+            Codec::Vp8 => "VP8",
+            Codec::Vp9 => "VP9",
+            Codec::H264 => "H264",
+            Codec::Av1 => "AV1",
+            Codec::Opus => "OPUS",
+            Codec::G722 => "G722",
+            Codec::Pcmu => "PCMU",
+            Codec::Pcma => "PCMA",
+        }
+    }
+
+    pub fn clock_rate(self) -> i32 {
+        match self {
+            Codec::Vp8 | Codec::Vp9 | Codec::H264 | Codec::Av1 => 90_000,
+            Codec::Opus => 48_000,
+            // G722 uses 8kHz as RTP clock rate for historical reasons, see RFC 3551
+            Codec::G722 | Codec::Pcmu | Codec::Pcma => 8_000,
+        }
+    }
+
+    // The static payload type from RFC 3551 if there is one, otherwise the dynamic payload type
+    // we use in our offers
+    pub fn default_payload_type(self) -> u32 {
+        match self {
+            Codec::Pcmu => 0,
+            Codec::Pcma => 8,
+            Codec::G722 => 9,
+            Codec::Opus => 97,
+            Codec::Vp8 | Codec::Vp9 | Codec::H264 | Codec::Av1 => 96,
+        }
+    }
+
+    fn from_encoding_name(name: &str) -> Option<Codec> {
+        Codec::VIDEO
+            .iter()
+            .chain(Codec::AUDIO.iter())
+            .find(|codec| codec.encoding_name().eq_ignore_ascii_case(name))
+            .copied()
+    }
+
+    // Elements for encoding raw audio or video, in gst-launch syntax
+    pub fn encoder(self) -> &'static str {
+        match self {
+            Codec::Vp8 => "vp8enc deadline=1",
+            Codec::Vp9 => "vp9enc deadline=1",
+            // Constrained baseline is supported by all browsers
+            Codec::H264 => {
+                "x264enc tune=zerolatency speed-preset=ultrafast key-int-max=60 ! \
+                 video/x-h264,profile=constrained-baseline"
+            }
+            Codec::Av1 => "av1enc cpu-used=8",
+            Codec::Opus => "opusenc",
+            Codec::G722 => "audioconvert ! audioresample ! avenc_g722",
+            Codec::Pcmu => "audioconvert ! audioresample ! mulawenc",
+            Codec::Pcma => "audioconvert ! audioresample ! alawenc",
+        }
+    }
+
+    // Factory name of the actual encoder element of encoder()
+    fn encoder_factory(self) -> &'static str {
+        match self {
+            Codec::Vp8 => "vp8enc",
+            Codec::Vp9 => "vp9enc",
+            Codec::H264 => "x264enc",
+            Codec::Av1 => "av1enc",
+            Codec::Opus => "opusenc",
+            Codec::G722 => "avenc_g722",
+            Codec::Pcmu => "mulawenc",
+            Codec::Pcma => "alawenc",
+        }
+    }
+
+    // Find the encoder of the codec among the children of the bin
+    pub fn find_encoder(self, bin: &gst::Bin) -> Option<gst::Element> {
+        bin.get_children().into_iter().find(|element| {
+            element
+                .get_factory()
+                .map(|factory| factory.get_name() == self.encoder_factory())
+                .unwrap_or(false)
+        })
+    }
+
+    // Payloader for the encoded stream with the given payload type, in gst-launch syntax
+    pub fn payloader(self, pt: u32) -> String {
+        let payloader = match self {
+            Codec::Vp8 => "rtpvp8pay",
+            Codec::Vp9 => "rtpvp9pay",
+            Codec::H264 => "rtph264pay config-interval=-1",
+            Codec::Av1 => "rtpav1pay",
+            Codec::Opus => "rtpopuspay",
+            Codec::G722 => "rtpg722pay",
+            Codec::Pcmu => "rtppcmupay",
+            Codec::Pcma => "rtppcmapay",
+        };
+
+        format!("{} pt={}", payloader, pt)
+    }
+
+    // RTP caps for the codec with the given payload type
+    pub fn caps(self, pt: u32) -> gst::Caps {
+        gst::Caps::new_simple(
+            "application/x-rtp",
+            &[
+                ("media", &self.media()),
+                ("encoding-name", &self.encoding_name()),
+                ("payload", &(pt as i32)),
+                ("clock-rate", &self.clock_rate()),
+            ],
+        )
+    }
+
+    // Plugins needed for encoding, payloading and decoding the codec
+    pub fn plugins(self) -> &'static [&'static str] {
+        match self {
+            Codec::Vp8 | Codec::Vp9 => &["vpx"],
+            Codec::H264 => &["x264", "videoparsersbad", "libav"],
+            Codec::Av1 => &["aom", "rsrtp", "videoparsersbad"],
+            Codec::Opus => &["opus"],
+            Codec::G722 => &["libav"],
+            Codec::Pcmu => &["mulaw"],
+            Codec::Pcma => &["alaw"],
+        }
+    }
+
+    fn is_available(self) -> bool {
+        let registry = gst::Registry::get();
+
+        self.plugins()
+            .iter()
+            .all(|plugin| registry.find_plugin(plugin).is_some())
+    }
+}
+
+impl std::fmt::Display for Codec {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(self.encoding_name())
+    }
+}
+
+// Change the target bitrate of a running encoder, in bits per second
+pub fn set_encoder_bitrate(encoder: &gst::Element, bitrate: u32) -> Result<(), anyhow::Error> {
+    let factory = encoder
+        .get_factory()
+        .map(|factory| factory.get_name().to_string())
+        .unwrap_or_default();
+
+    // Some encoders take the bitrate in kbit/s
+    match factory.as_str() {
+        "vp8enc" | "vp9enc" => {
+            encoder.set_property_from_str("target-bitrate", &bitrate.to_string())
+        }
+        "x264enc" => encoder.set_property_from_str("bitrate", &(bitrate / 1000).to_string()),
+        "av1enc" => encoder.set_property_from_str("target-bitrate", &(bitrate / 1000).to_string()),
+        "opusenc" => encoder.set_property_from_str("bitrate", &bitrate.to_string()),
+        _ => bail!("Encoder {} has no configurable bitrate", factory),
+    }
+
+    Ok(())
+}
+
+// An upstream event asking for a keyframe as soon as possible
+fn force_key_unit() -> gst::Event {
+    // A running time of GST_CLOCK_TIME_NONE means as soon as possible
+    gst::event::CustomUpstream::new(gst::Structure::new(
+        "GstForceKeyUnit",
+        &[
+            ("running-time", &u64::MAX),
+            ("all-headers", &true),
+            ("count", &0u32),
+        ],
+    ))
+}
+
+// Ask a running encoder to produce a keyframe as soon as possible
+pub fn request_keyframe(encoder: &gst::Element) -> Result<(), anyhow::Error> {
+    let srcpad = encoder
+        .get_static_pad("src")
+        .ok_or_else(|| anyhow::anyhow!("Encoder has no src pad"))?;
+    if !srcpad.send_event(force_key_unit()) {
+        bail!("Encoder didn't handle the keyframe request");
+    }
+
+    Ok(())
+}
+
+// Ask whatever produces the stream arriving at this sink pad for a keyframe. For a stream received
+// by webrtcbin, it sends a PLI to the remote sender
+pub fn request_keyframe_upstream(sinkpad: &gst::Pad) -> Result<(), anyhow::Error> {
+    if !sinkpad.push_event(force_key_unit()) {
+        bail!("Nothing upstream handled the keyframe request");
+    }
+
+    Ok(())
+}
+
+fn parse_codec(s: &str, codecs: &[Codec]) -> Result<Codec, anyhow::Error> {
+    match codecs
+        .iter()
+        .find(|codec| codec.encoding_name().eq_ignore_ascii_case(s))
+    {
+        Some(codec) => Ok(*codec),
+        None => bail!(
+            "Invalid codec: {}. Use one of {}",
+            s,
+            codecs
+                .iter()
+                .map(|codec| codec.encoding_name().to_lowercase())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    }
+}
+
+fn parse_video_codec(s: &str) -> Result<Codec, anyhow::Error> {
+    parse_codec(s, &Codec::VIDEO)
+}
+
+fn parse_audio_codec(s: &str) -> Result<Codec, anyhow::Error> {
+    parse_codec(s, &Codec::AUDIO)
+}
+
+// Value of the first attribute with the given key for the payload type, e.g. "VP8/90000" for
+// "a=rtpmap:96 VP8/90000"
+fn format_attribute<'a>(media: &'a gst_sdp::SDPMediaRef, key: &str, pt: u32) -> Option<&'a str> {
+    (0..)
+        .map(|nth| media.get_attribute_val_n(key, nth))
+        .take_while(Option::is_some)
+        .flatten()
+        .find_map(|val| {
+            let mut split = val.splitn(2, ' ');
+            match (
+                split.next().and_then(|val_pt| val_pt.parse::<u32>().ok()),
+                split.next(),
+            ) {
+                (Some(val_pt), Some(val)) if val_pt == pt => Some(val),
+                _ => None,
+            }
+        })
+}
+
+// Whether we can send H264 with the format parameters of the fmtp attribute. rtph264pay fragments
+// large NAL units, which needs packetization-mode 1, and our encoder produces constrained
+// baseline, which is understood by all peers offering a baseline profile
+fn h264_format_supported(fmtp: Option<&str>) -> bool {
+    // The defaults from RFC 6184
+    let mut packetization_mode = "0";
+    let mut profile_level_id = "42000a";
+    for param in fmtp.into_iter().flat_map(|fmtp| fmtp.split(';')) {
+        let mut split = param.trim().splitn(2, '=');
+        match (split.next(), split.next()) {
+            (Some("packetization-mode"), Some(mode)) => packetization_mode = mode.trim(),
+            (Some("profile-level-id"), Some(id)) => profile_level_id = id.trim(),
+            _ => (),
+        }
+    }
+
+    let profile_idc = match u32::from_str_radix(profile_level_id, 16) {
+        Ok(id) if profile_level_id.len() == 6 => id >> 16,
+        _ => return false,
+    };
+
+    packetization_mode == "1" && profile_idc == 0x42
+}
+
+// Codecs offered in one media of an SDP together with their payload types, in the order of
+// preference of the peer. Codecs we don't know or can't send with the offered parameters are
+// skipped
+pub fn offered_codecs(media: &gst_sdp::SDPMediaRef) -> Vec<(Codec, u32)> {
+    let mut codecs = Vec::new();
+
+    for idx in 0..media.formats_len() {
+        let pt = match media.get_format(idx).and_then(|pt| pt.parse::<u32>().ok()) {
+            Some(pt) => pt,
+            None => continue,
+        };
+
+        // Static payload types don't need an rtpmap attribute
+        let codec = match format_attribute(media, "rtpmap", pt) {
+            Some(rtpmap) => rtpmap.split('/').next().and_then(Codec::from_encoding_name),
+            None if pt < 96 => Codec::AUDIO
+                .iter()
+                .find(|codec| codec.default_payload_type() == pt)
+                .copied(),
+            None => None,
+        };
+
+        let codec = match codec {
+            Some(Codec::H264) if !h264_format_supported(format_attribute(media, "fmtp", pt)) => {
+                continue
+            }
+            Some(codec) if Some(codec.media()) == media.get_media() => codec,
+            _ => continue,
+        };
+
+        codecs.push((codec, pt));
+    }
+
+    codecs
+}
+
+// Command line arguments for the codecs we send, to be flattened into the Args of each demo
+#[derive(Debug, Clone, StructOpt)]
+pub struct CodecArgs {
+    // vp8, vp9, h264 or av1
+    #[structopt(long, default_value = "vp8", parse(try_from_str = parse_video_codec))]
+    pub video_codec: Codec,
+    // opus, g722, pcmu or pcma
+    #[structopt(long, default_value = "opus", parse(try_from_str = parse_audio_codec))]
+    pub audio_codec: Codec,
+}
+
+impl CodecArgs {
+    // Plugins needed for the configured codecs
+    pub fn plugins(&self) -> Vec<&'static str> {
+        let mut plugins = vec!["rtp"];
+        plugins.extend(self.video_codec.plugins());
+        plugins.extend(self.audio_codec.plugins());
+
+        plugins
+    }
+
+    // Codec we're configured to use for the media type
+    pub fn codec(&self, media: &str) -> Option<Codec> {
+        match media {
+            "video" => Some(self.video_codec),
+            "audio" => Some(self.audio_codec),
+            _ => None,
+        }
+    }
+
+    // Pick the codec for answering one media of an offer: our configured codec if the peer
+    // offered it, otherwise the first offered codec we have the plugins for
+    pub fn select_from_offer(&self, media: &gst_sdp::SDPMediaRef) -> Option<(Codec, u32)> {
+        let offered = offered_codecs(media);
+
+        offered
+            .iter()
+            .find(|(codec, _)| Some(*codec) == media.get_media().and_then(|m| self.codec(m)))
+            .or_else(|| offered.iter().find(|(codec, _)| codec.is_available()))
+            .copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Offer similar to the ones of browsers, with the lines the codec selection doesn't look at
+    // left out
+    const OFFER: &str = concat!(
+        "v=0\r\n",
+        "o=- 0 0 IN IP4 0.0.0.0\r\n",
+        "s=-\r\n",
+        "t=0 0\r\n",
+        "m=audio 9 UDP/TLS/RTP/SAVPF 111 9 0 8 13\r\n",
+        "a=rtpmap:111 opus/48000/2\r\n",
+        "a=fmtp:111 minptime=10;useinbandfec=1\r\n",
+        "a=rtpmap:9 G722/8000\r\n",
+        "a=rtpmap:13 CN/8000\r\n",
+        "m=video 9 UDP/TLS/RTP/SAVPF 96 97 102 127 125 108 35\r\n",
+        "a=rtpmap:96 VP8/90000\r\n",
+        "a=rtpmap:97 rtx/90000\r\n",
+        "a=fmtp:97 apt=96\r\n",
+        "a=rtpmap:102 H264/90000\r\n",
+        "a=fmtp:102 level-asymmetry-allowed=1;packetization-mode=0;profile-level-id=42e01f\r\n",
+        "a=rtpmap:127 H264/90000\r\n",
+        "a=fmtp:127 level-asymmetry-allowed=1;packetization-mode=1;profile-level-id=4d001f\r\n",
+        "a=rtpmap:125 H264/90000\r\n",
+        "a=fmtp:125 level-asymmetry-allowed=1;packetization-mode=1;profile-level-id=42e01f\r\n",
+        "a=rtpmap:108 H264/90000\r\n",
+        "a=rtpmap:35 AV1/90000\r\n",
+        "m=application 9 UDP/DTLS/SCTP webrtc-datachannel\r\n",
+    );
+
+    fn offer() -> gst_sdp::SDPMessage {
+        gst_sdp::SDPMessage::parse_buffer(OFFER.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn parse_codecs() {
+        assert_eq!(parse_video_codec("vp9").unwrap(), Codec::Vp9);
+        assert_eq!(parse_video_codec("H264").unwrap(), Codec::H264);
+        assert_eq!(parse_audio_codec("pcma").unwrap(), Codec::Pcma);
+        assert!(parse_video_codec("opus").is_err());
+        assert!(parse_audio_codec("h265").is_err());
+    }
+
+    #[test]
+    fn offered_audio_codecs() {
+        let offer = offer();
+
+        // Static payload types without rtpmap and unknown codecs
+        assert_eq!(
+            offered_codecs(offer.get_media(0).unwrap()),
+            vec![
+                (Codec::Opus, 111),
+                (Codec::G722, 9),
+                (Codec::Pcmu, 0),
+                (Codec::Pcma, 8)
+            ]
+        );
+    }
+
+    #[test]
+    fn offered_video_codecs() {
+        let offer = offer();
+
+        // Only H264 with packetization mode 1 and a baseline profile, and no RTX
+        assert_eq!(
+            offered_codecs(offer.get_media(1).unwrap()),
+            vec![(Codec::Vp8, 96), (Codec::H264, 125), (Codec::Av1, 35)]
+        );
+        assert!(offered_codecs(offer.get_media(2).unwrap()).is_empty());
+    }
+
+    #[test]
+    fn h264_format_parameters() {
+        assert!(h264_format_supported(Some(
+            "packetization-mode=1;profile-level-id=42e01f"
+        )));
+        assert!(h264_format_supported(Some(
+            "profile-level-id=42001f; packetization-mode=1"
+        )));
+        assert!(!h264_format_supported(Some(
+            "packetization-mode=0;profile-level-id=42e01f"
+        )));
+        assert!(!h264_format_supported(Some(
+            "packetization-mode=1;profile-level-id=640032"
+        )));
+        assert!(!h264_format_supported(Some(
+            "packetization-mode=1;profile-level-id=42e0"
+        )));
+        // Defaults to packetization mode 0
+        assert!(!h264_format_supported(None));
+    }
+
+    #[test]
+    fn select_configured_codec() {
+        let offer = offer();
+        let args = CodecArgs {
+            video_codec: Codec::H264,
+            audio_codec: Codec::Pcma,
+        };
+
+        assert_eq!(
+            args.select_from_offer(offer.get_media(0).unwrap()),
+            Some((Codec::Pcma, 8))
+        );
+        assert_eq!(
+            args.select_from_offer(offer.get_media(1).unwrap()),
+            Some((Codec::H264, 125))
+        );
+    }
+}
@@ -0,0 +1,238 @@
+// Monitoring of the connection states of webrtcbin and recovery from ICE failures
+//
+// All state changes are logged, and written to the statistics output if there is one. If the ICE
+// connection fails, or stays disconnected for too long, the offerer restarts ICE. If the
+// connection doesn't recover in time after that, the call is considered failed.
+
+use std::fmt::Debug;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+use async_std::task;
+
+use gst::prelude::*;
+use gst_webrtc::{WebRTCICEConnectionState, WebRTCICEGatheringState, WebRTCPeerConnectionState};
+
+use crate::{IceArgs, StatsReporter};
+
+#[derive(Debug)]
+struct State {
+    ice_connection_state: WebRTCICEConnectionState,
+    // Incremented whenever ICE is connected, timers started before are outdated then
+    connected_count: u64,
+    restarting: bool,
+}
+
+struct Watch {
+    name: String,
+    peer_id: Option<u32>,
+    stats_reporter: Option<StatsReporter>,
+    disconnected_timeout: Duration,
+    restart_timeout: Duration,
+    restart: Option<Box<dyn Fn() + Send + Sync>>,
+    failed: Box<dyn Fn(String) + Send + Sync>,
+    state: Mutex<State>,
+}
+
+impl Watch {
+    // Write an event line for this connection to the statistics output, if any
+    fn write_event(&self, type_: &str, fields: serde_json::Map<String, serde_json::Value>) {
+        if let Some(ref stats_reporter) = self.stats_reporter {
+            if let Err(err) = stats_reporter.write_event(type_, self.peer_id, fields) {
+                println!("Failed to write statistics: {:?}", err);
+            }
+        }
+    }
+
+    fn on_state<T: Debug>(&self, type_: &str, description: &str, state: T) {
+        println!("{}: {} changed to {:?}", self.name, description, state);
+
+        let mut fields = serde_json::Map::new();
+        fields.insert(String::from("state"), state_name(state).into());
+        self.write_event(type_, fields);
+    }
+
+    // Call the function after the timeout unless ICE got connected in the meantime
+    fn unless_connected_after<F: FnOnce(&Watch) + Send + 'static>(
+        self: &Arc<Self>,
+        timeout: Duration,
+        func: F,
+    ) {
+        let watch = Arc::downgrade(self);
+        let connected_count = self.state.lock().unwrap().connected_count;
+
+        task::spawn(async move {
+            task::sleep(timeout).await;
+
+            if let Some(watch) = Weak::upgrade(&watch) {
+                if watch.state.lock().unwrap().connected_count == connected_count {
+                    func(&watch);
+                }
+            }
+        });
+    }
+
+    fn on_ice_connection_state(self: &Arc<Self>, ice_connection_state: WebRTCICEConnectionState) {
+        self.on_state(
+            "ice-connection-state",
+            "ICE connection state",
+            ice_connection_state,
+        );
+
+        let mut state = self.state.lock().unwrap();
+        state.ice_connection_state = ice_connection_state;
+
+        match ice_connection_state {
+            WebRTCICEConnectionState::Connected | WebRTCICEConnectionState::Completed => {
+                state.connected_count += 1;
+                if state.restarting {
+                    println!("{}: ICE connection recovered", self.name);
+                    state.restarting = false;
+                }
+            }
+            WebRTCICEConnectionState::Disconnected if !state.restarting => {
+                drop(state);
+
+                // Disconnections are often temporary, so give them some time before restarting
+                self.unless_connected_after(self.disconnected_timeout, |watch| {
+                    let state = watch.state.lock().unwrap();
+                    if state.ice_connection_state == WebRTCICEConnectionState::Disconnected {
+                        drop(state);
+                        watch.restart_ice("disconnected");
+                    }
+                });
+            }
+            WebRTCICEConnectionState::Failed if !state.restarting => {
+                drop(state);
+                self.restart_ice("failed");
+            }
+            _ => (),
+        }
+    }
+
+    // Restart ICE if we're the offerer, or wait for the peer to do so otherwise
+    fn restart_ice(self: &Arc<Self>, reason: &str) {
+        let mut state = self.state.lock().unwrap();
+        if state.restarting {
+            return;
+        }
+        state.restarting = true;
+        drop(state);
+
+        let mut fields = serde_json::Map::new();
+        fields.insert(String::from("reason"), reason.into());
+        fields.insert(String::from("local"), self.restart.is_some().into());
+        self.write_event("ice-restart", fields);
+
+        if let Some(ref restart) = self.restart {
+            println!("{}: ICE connection {}, restarting ICE", self.name, reason);
+            restart();
+        } else {
+            println!(
+                "{}: ICE connection {}, waiting for the peer to restart ICE",
+                self.name, reason
+            );
+        }
+
+        let restart_timeout = self.restart_timeout;
+        self.unless_connected_after(restart_timeout, move |watch| {
+            let mut fields = serde_json::Map::new();
+            fields.insert(String::from("timeout"), restart_timeout.as_secs().into());
+            watch.write_event("connection-failed", fields);
+
+            (watch.failed)(format!(
+                "{}: ICE connection could not be restored within {}s",
+                watch.name,
+                restart_timeout.as_secs()
+            ));
+        });
+    }
+}
+
+// Lower case name of a state enum value, as used in the statistics output
+fn state_name<T: Debug>(state: T) -> String {
+    format!("{:?}", state).to_lowercase()
+}
+
+// Log all connection state changes of the webrtcbin and handle ICE failures. The changes are also
+// written as events for the peer to the statistics output if one is passed. The offerer passes a
+// `restart` function that creates an offer with ICE restart, `failed` is called once the
+// connection couldn't be restored
+pub fn watch_connection<R, F>(
+    webrtcbin: &gst::Element,
+    name: &str,
+    peer_id: Option<u32>,
+    args: &IceArgs,
+    stats_reporter: Option<&StatsReporter>,
+    restart: Option<R>,
+    failed: F,
+) where
+    R: Fn() + Send + Sync + 'static,
+    F: Fn(String) + Send + Sync + 'static,
+{
+    let watch = Arc::new(Watch {
+        name: name.to_string(),
+        peer_id,
+        stats_reporter: stats_reporter.cloned(),
+        disconnected_timeout: Duration::from_secs(args.ice_disconnected_timeout),
+        restart_timeout: Duration::from_secs(args.ice_restart_timeout),
+        restart: restart.map(|restart| Box::new(restart) as Box<dyn Fn() + Send + Sync>),
+        failed: Box::new(failed),
+        state: Mutex::new(State {
+            ice_connection_state: WebRTCICEConnectionState::New,
+            connected_count: 0,
+            restarting: false,
+        }),
+    });
+
+    let watch_clone = watch.clone();
+    webrtcbin.connect_notify(Some("ice-connection-state"), move |webrtcbin, _pspec| {
+        let ice_connection_state = webrtcbin
+            .get_property("ice-connection-state")
+            .unwrap()
+            .get_some::<WebRTCICEConnectionState>()
+            .unwrap();
+        watch_clone.on_ice_connection_state(ice_connection_state);
+    });
+
+    let watch_clone = watch.clone();
+    webrtcbin.connect_notify(Some("connection-state"), move |webrtcbin, _pspec| {
+        let connection_state = webrtcbin
+            .get_property("connection-state")
+            .unwrap()
+            .get_some::<WebRTCPeerConnectionState>()
+            .unwrap();
+        watch_clone.on_state("connection-state", "connection state", connection_state);
+    });
+
+    webrtcbin.connect_notify(Some("ice-gathering-state"), move |webrtcbin, _pspec| {
+        let ice_gathering_state = webrtcbin
+            .get_property("ice-gathering-state")
+            .unwrap()
+            .get_some::<WebRTCICEGatheringState>()
+            .unwrap();
+        watch.on_state(
+            "ice-gathering-state",
+            "ICE gathering state",
+            ice_gathering_state,
+        );
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn state_names() {
+        assert_eq!(
+            state_name(WebRTCICEConnectionState::Disconnected),
+            "disconnected"
+        );
+        assert_eq!(
+            state_name(WebRTCPeerConnectionState::Connected),
+            "connected"
+        );
+        assert_eq!(state_name(WebRTCICEGatheringState::Complete), "complete");
+    }
+}
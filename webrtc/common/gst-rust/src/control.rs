@@ -0,0 +1,364 @@
+// Runtime control of the demos over a Unix domain socket
+//
+// Each line sent to the socket is a JSON command like {"command": "mute", "media": "audio"} and
+// is answered with a line like {"ok": true} or {"ok": false, "error": "..."}. Commands with a
+// result, like dump-stats, include it as "result".
+
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+
+use structopt::StructOpt;
+
+use futures::channel::{mpsc, oneshot};
+use futures::stream::{self, BoxStream, StreamExt};
+
+use serde_derive::{Deserialize, Serialize};
+
+use gst::prelude::*;
+
+use anyhow::{anyhow, bail, Context};
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "command", rename_all = "kebab-case")]
+pub enum Command {
+    // media is either audio or video
+    Mute { media: String },
+    Unmute { media: String },
+    // In bits per second
+    SetBitrate { media: String, bitrate: u32 },
+    // Replace the test source by another live source, e.g. videotestsrc pattern=snow is-live=true
+    SwapSource { media: String, source: String },
+    RequestKeyframe,
+    DumpStats,
+    Hangup,
+    // Remove a peer from the call, only in multiparty
+    Kick { peer_id: u32 },
+    // Switch the video layout to grid, speaker, pip or a JSON layout file, only in multiparty
+    SetLayout { layout: String },
+    // Show a peer largest in the speaker and pip layouts, only in multiparty
+    Focus { peer_id: u32 },
+}
+
+#[derive(Serialize, Debug)]
+struct Response {
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<serde_json::Value>,
+}
+
+// A command received on the control socket, which has to be answered once handled
+#[derive(Debug)]
+pub struct ControlRequest {
+    pub command: Command,
+    reply: oneshot::Sender<Response>,
+}
+
+impl ControlRequest {
+    // Answer the request with the outcome of the command and its result, if any
+    pub fn reply(self, res: Result<Option<serde_json::Value>, anyhow::Error>) {
+        let response = match res {
+            Ok(result) => Response {
+                ok: true,
+                error: None,
+                result,
+            },
+            Err(err) => Response {
+                ok: false,
+                error: Some(format!("{:#}", err)),
+                result: None,
+            },
+        };
+
+        // The client might be gone already
+        let _ = self.reply.send(response);
+    }
+}
+
+// Replace a running source element by a new one created from a gst-launch description, which
+// takes over its name and is linked to the same downstream pad. The new source should be live,
+// like the test sources, so that its timestamps continue where the old one stopped
+pub fn swap_source(source: &gst::Element, description: &str) -> Result<(), anyhow::Error> {
+    let bin = source
+        .get_parent()
+        .and_then(|parent| parent.downcast::<gst::Bin>().ok())
+        .ok_or_else(|| anyhow!("Source is not in a bin"))?;
+    let srcpad = source.get_static_pad("src").unwrap();
+    let peer = srcpad
+        .get_peer()
+        .ok_or_else(|| anyhow!("Source is not linked"))?;
+
+    let new_source = gst::parse_bin_from_description(description, true)
+        .with_context(|| format!("Invalid source {}", description))?;
+
+    srcpad.unlink(&peer)?;
+    source.set_state(gst::State::Null)?;
+    bin.remove(source)?;
+
+    new_source.set_name(&source.get_name())?;
+    bin.add(&new_source)?;
+    new_source.get_static_pad("src").unwrap().link(&peer)?;
+    new_source.sync_state_with_parent()?;
+
+    Ok(())
+}
+
+// Command line arguments for the control socket, to be flattened into the Args of each demo
+#[derive(Debug, Clone, StructOpt)]
+pub struct ControlArgs {
+    // Accept control commands on this Unix domain socket
+    #[structopt(long, parse(from_os_str))]
+    pub control_socket: Option<PathBuf>,
+}
+
+// Removes the socket file once dropped
+struct SocketFile(PathBuf);
+
+impl Drop for SocketFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+impl ControlArgs {
+    // Start listening on the control socket if one was configured. Incoming commands are passed
+    // to the returned stream, which stays pending forever otherwise. The socket file is removed
+    // again when the stream is dropped on shutdown
+    pub fn listen(&self) -> Result<BoxStream<'static, ControlRequest>, anyhow::Error> {
+        match self.control_socket {
+            Some(ref path) => {
+                let (request_tx, request_rx) = mpsc::unbounded();
+                listen(path, request_tx)?;
+
+                let socket_file = SocketFile(path.clone());
+                Ok(request_rx
+                    .map(move |request| {
+                        let _ = &socket_file;
+                        request
+                    })
+                    .boxed())
+            }
+            None => Ok(stream::pending().boxed()),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn listen(
+    path: &Path,
+    request_tx: mpsc::UnboundedSender<ControlRequest>,
+) -> Result<(), anyhow::Error> {
+    use std::os::unix::net::{UnixListener, UnixStream};
+
+    fn handle_client(
+        stream: UnixStream,
+        request_tx: &mpsc::UnboundedSender<ControlRequest>,
+    ) -> Result<(), anyhow::Error> {
+        let mut writer = stream.try_clone()?;
+
+        for line in BufReader::new(stream).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let response = match serde_json::from_str::<Command>(&line) {
+                Ok(command) => {
+                    let (reply, response) = oneshot::channel();
+                    request_tx.unbounded_send(ControlRequest { command, reply })?;
+                    match futures::executor::block_on(response) {
+                        Ok(response) => response,
+                        // The application is shutting down
+                        Err(_) => break,
+                    }
+                }
+                Err(err) => Response {
+                    ok: false,
+                    error: Some(format!("Invalid command: {}", err)),
+                    result: None,
+                },
+            };
+
+            writeln!(writer, "{}", serde_json::to_string(&response)?)?;
+        }
+
+        Ok(())
+    }
+
+    // Remove a stale socket of an earlier run, but nothing else
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        use std::os::unix::fs::FileTypeExt;
+
+        if !metadata.file_type().is_socket() {
+            bail!("{} exists and is not a socket", path.display());
+        }
+        std::fs::remove_file(path)?;
+    }
+
+    let listener = UnixListener::bind(path)
+        .with_context(|| format!("Can't listen on control socket {}", path.display()))?;
+    println!("Accepting control commands on {}", path.display());
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(err) => {
+                    println!("Failed to accept control connection: {:?}", err);
+                    continue;
+                }
+            };
+
+            let request_tx = request_tx.clone();
+            thread::spawn(move || {
+                if let Err(err) = handle_client(stream, &request_tx) {
+                    println!("Control connection failed: {:?}", err);
+                }
+            });
+        }
+    });
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn listen(
+    path: &Path,
+    _request_tx: mpsc::UnboundedSender<ControlRequest>,
+) -> Result<(), anyhow::Error> {
+    bail!(
+        "Control socket {} not supported, Unix domain sockets are not available on this platform",
+        path.display()
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(line: &str) -> Result<Command, serde_json::Error> {
+        serde_json::from_str(line)
+    }
+
+    // The line written back to the client for the outcome of a command
+    fn response(res: Result<Option<serde_json::Value>, anyhow::Error>) -> String {
+        let (reply, response) = oneshot::channel();
+        ControlRequest {
+            command: Command::Hangup,
+            reply,
+        }
+        .reply(res);
+
+        serde_json::to_string(&futures::executor::block_on(response).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn parse_commands() {
+        assert_eq!(
+            parse(r#"{"command": "mute", "media": "audio"}"#).unwrap(),
+            Command::Mute {
+                media: "audio".into()
+            }
+        );
+        assert_eq!(
+            parse(r#"{"command": "set-bitrate", "media": "video", "bitrate": 500000}"#).unwrap(),
+            Command::SetBitrate {
+                media: "video".into(),
+                bitrate: 500_000
+            }
+        );
+        assert_eq!(
+            parse(r#"{"command": "swap-source", "media": "video", "source": "videotestsrc"}"#)
+                .unwrap(),
+            Command::SwapSource {
+                media: "video".into(),
+                source: "videotestsrc".into()
+            }
+        );
+        assert_eq!(
+            parse(r#"{"command": "request-keyframe"}"#).unwrap(),
+            Command::RequestKeyframe
+        );
+        assert_eq!(
+            parse(r#"{"command": "dump-stats"}"#).unwrap(),
+            Command::DumpStats
+        );
+        assert_eq!(
+            parse(r#"{"command": "kick", "peer_id": 1234}"#).unwrap(),
+            Command::Kick { peer_id: 1234 }
+        );
+        assert_eq!(
+            parse(r#"{"command": "set-layout", "layout": "pip"}"#).unwrap(),
+            Command::SetLayout {
+                layout: "pip".into()
+            }
+        );
+    }
+
+    #[test]
+    fn parse_invalid_commands() {
+        assert!(parse(r#"{"command": "reboot"}"#).is_err());
+        assert!(parse(r#"{"media": "audio"}"#).is_err());
+        assert!(parse(r#"{"command": "mute"}"#).is_err());
+        assert!(parse(r#"{"command": "set-bitrate", "media": "video", "bitrate": -1}"#).is_err());
+        assert!(parse(r#"{"command": "focus", "peer_id": "abc"}"#).is_err());
+        assert!(parse("mute audio").is_err());
+    }
+
+    #[test]
+    fn serialize_responses() {
+        assert_eq!(response(Ok(None)), r#"{"ok":true}"#);
+        assert_eq!(
+            response(Ok(Some(serde_json::json!([1, 2])))),
+            r#"{"ok":true,"result":[1,2]}"#
+        );
+        assert_eq!(
+            response(Err(anyhow!("No video source"))),
+            r#"{"ok":false,"error":"No video source"}"#
+        );
+        // With the whole chain of causes
+        assert_eq!(
+            response(Err(anyhow!("No such element").context("Invalid source foo"))),
+            r#"{"ok":false,"error":"Invalid source foo: No such element"}"#
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn control_socket() {
+        use std::os::unix::net::UnixStream;
+
+        let path = std::env::temp_dir().join(format!("webrtc-control-{}", std::process::id()));
+        let args = ControlArgs {
+            control_socket: Some(path.clone()),
+        };
+        let mut requests = args.listen().unwrap();
+
+        let stream = UnixStream::connect(&path).unwrap();
+        let mut writer = stream.try_clone().unwrap();
+        let mut lines = BufReader::new(stream).lines();
+
+        // Invalid commands are answered right away
+        writeln!(writer, r#"{{"command": "reboot"}}"#).unwrap();
+        let line = lines.next().unwrap().unwrap();
+        assert!(line.starts_with(r#"{"ok":false,"error":"Invalid command: "#));
+
+        writeln!(writer).unwrap();
+        writeln!(writer, r#"{{"command": "unmute", "media": "video"}}"#).unwrap();
+        let request = futures::executor::block_on(requests.next()).unwrap();
+        assert_eq!(
+            request.command,
+            Command::Unmute {
+                media: "video".into()
+            }
+        );
+        request.reply(Ok(None));
+        assert_eq!(lines.next().unwrap().unwrap(), r#"{"ok":true}"#);
+
+        // Shutting down removes the socket
+        drop(requests);
+        assert!(!path.exists());
+    }
+}
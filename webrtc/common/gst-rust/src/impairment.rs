@@ -0,0 +1,132 @@
+// Emulation of bad networks with packet loss, delay and jitter
+//
+// A netsim element is placed between the payloaders and webrtcbin and/or between webrtcbin and
+// the depayloaders. Losses on the send path are seen by the peer like losses on the network and
+// can be recovered with retransmissions and FEC, while losses on the receive path happen after
+// the jitterbuffer and can't be recovered anymore.
+
+use structopt::StructOpt;
+
+use gst::prelude::*;
+
+use anyhow::{bail, Context};
+
+// Which streams are impaired
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImpairmentPath {
+    Send,
+    Receive,
+    Both,
+}
+
+impl std::str::FromStr for ImpairmentPath {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "send" => Ok(ImpairmentPath::Send),
+            "receive" => Ok(ImpairmentPath::Receive),
+            "both" => Ok(ImpairmentPath::Both),
+            _ => bail!("Invalid impairment path: {}. Use send, receive or both", s),
+        }
+    }
+}
+
+// Command line arguments for the network impairment, to be flattened into the Args of each demo
+#[derive(Debug, Clone, StructOpt)]
+pub struct ImpairmentArgs {
+    // Probability between 0 and 1 to drop each RTP packet
+    #[structopt(long, default_value = "0", parse(try_from_str = parse_probability))]
+    pub drop_probability: f32,
+    // Delay of each RTP packet in milliseconds
+    #[structopt(long, default_value = "0")]
+    pub delay: u32,
+    // Maximum deviation from the delay in milliseconds, which also reorders packets
+    #[structopt(long, default_value = "0")]
+    pub jitter: u32,
+    // Impair the sent streams, the received streams or both
+    #[structopt(long, default_value = "both")]
+    pub impair: ImpairmentPath,
+}
+
+fn parse_probability(s: &str) -> Result<f32, anyhow::Error> {
+    let probability = s
+        .parse::<f32>()
+        .with_context(|| format!("Invalid probability {}", s))?;
+    if !(0.0..=1.0).contains(&probability) {
+        bail!(
+            "Invalid probability {}, must be between 0 and 1",
+            probability
+        );
+    }
+
+    Ok(probability)
+}
+
+impl ImpairmentArgs {
+    fn is_enabled(&self) -> bool {
+        self.drop_probability > 0.0 || self.delay > 0 || self.jitter > 0
+    }
+
+    fn impairs_send(&self) -> bool {
+        self.is_enabled() && self.impair != ImpairmentPath::Receive
+    }
+
+    fn impairs_receive(&self) -> bool {
+        self.is_enabled() && self.impair != ImpairmentPath::Send
+    }
+
+    // Plugins needed for the impairment, if any
+    pub fn plugins(&self) -> Vec<&'static str> {
+        if self.is_enabled() {
+            vec!["netsim"]
+        } else {
+            Vec::new()
+        }
+    }
+
+    // The netsim element in gst-launch syntax
+    fn description(&self) -> String {
+        let min_delay = self.delay.saturating_sub(self.jitter);
+        let max_delay = self.delay + self.jitter;
+        let delay_probability = if max_delay > 0 { 1.0 } else { 0.0 };
+
+        format!(
+            "netsim drop-probability={} delay-probability={} min-delay={} max-delay={}",
+            self.drop_probability, delay_probability, min_delay, max_delay
+        )
+    }
+
+    // What to append to a payloader in a gst-launch description to impair the sent stream, which
+    // is empty unless configured
+    pub fn send_stage(&self) -> String {
+        if self.impairs_send() {
+            format!(" ! {}", self.description())
+        } else {
+            String::new()
+        }
+    }
+
+    // Link a pad of webrtcbin with an incoming stream to the sink pad, with the impairment in
+    // between if configured. The netsim element is added to the bin
+    pub fn link_incoming<P: IsA<gst::Pad>>(
+        &self,
+        bin: &gst::Bin,
+        pad: &gst::Pad,
+        sinkpad: &P,
+    ) -> Result<(), anyhow::Error> {
+        if !self.impairs_receive() {
+            pad.link(sinkpad)?;
+            return Ok(());
+        }
+
+        let netsim = gst::parse_launch(&self.description())?;
+        bin.add(&netsim)?;
+        netsim.sync_state_with_parent()?;
+
+        pad.link(&netsim.get_static_pad("sink").unwrap())?;
+        netsim.get_static_pad("src").unwrap().link(sinkpad)?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,25 @@
+// Helpers shared by the Rust webrtcbin demos
+
+mod codecs;
+mod connection;
+mod control;
+mod ice;
+mod impairment;
+mod metrics;
+mod recovery;
+mod stats;
+
+pub use codecs::{
+    offered_codecs, request_keyframe, request_keyframe_upstream, set_encoder_bitrate, Codec,
+    CodecArgs,
+};
+pub use connection::watch_connection;
+pub use control::{swap_source, Command, ControlArgs, ControlRequest};
+pub use ice::{IceArgs, IceTransportPolicy};
+pub use impairment::{ImpairmentArgs, ImpairmentPath};
+pub use metrics::{Metrics, MetricsArgs};
+pub use recovery::{FecType, RecoveryArgs};
+pub use stats::{
+    parse_stats, request_stats, CandidatePair, InboundRtp, OutboundRtp, RemoteInboundRtp, Stats,
+    StatsArgs, StatsKind, StatsPoller, StatsReporter, Transport,
+};
@@ -0,0 +1,604 @@
+// Prometheus metrics of the webrtcbins and pipeline of a demo
+//
+// The statistics of each watched webrtcbin are polled every second and served together with the
+// connection states and the number of errors and warnings on the bus in the Prometheus text
+// format on /metrics.
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use structopt::StructOpt;
+
+use gst::prelude::*;
+use gst_webrtc::WebRTCPeerConnectionState;
+
+use anyhow::Context;
+
+use crate::stats::{Stats, StatsKind, StatsPoller};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+// Clients that don't send their request or read the response in time are disconnected
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+const CONNECTION_STATES: [(WebRTCPeerConnectionState, &str); 6] = [
+    (WebRTCPeerConnectionState::New, "new"),
+    (WebRTCPeerConnectionState::Connecting, "connecting"),
+    (WebRTCPeerConnectionState::Connected, "connected"),
+    (WebRTCPeerConnectionState::Disconnected, "disconnected"),
+    (WebRTCPeerConnectionState::Failed, "failed"),
+    (WebRTCPeerConnectionState::Closed, "closed"),
+];
+
+// Command line arguments for the metrics endpoint, to be flattened into the Args of each demo
+#[derive(Debug, Clone, StructOpt)]
+pub struct MetricsArgs {
+    // Serve Prometheus metrics on http://ADDR/metrics, e.g. 127.0.0.1:9100
+    #[structopt(long)]
+    pub metrics_addr: Option<SocketAddr>,
+}
+
+#[derive(Debug)]
+struct PeerMetrics {
+    connection_state: WebRTCPeerConnectionState,
+    stats: Vec<Stats>,
+    // In bits per second by the id of the RTP stream, computed from the last two reports
+    bitrates: BTreeMap<String, f64>,
+    frames_decoded: u64,
+    // Path of the bin with the elements of this peer, if it has its own
+    bin_path: Option<String>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    peers: BTreeMap<String, PeerMetrics>,
+    bus_errors: u64,
+    bus_warnings: u64,
+    // Frames dropped according to the QoS messages by the path of the element
+    frames_dropped: BTreeMap<String, u64>,
+}
+
+// Collects the metrics of any number of peers and serves them over HTTP
+#[derive(Debug, Clone)]
+pub struct Metrics(Arc<Mutex<State>>);
+
+impl Metrics {
+    // Start serving the metrics if an address was given on the command line
+    pub fn new(args: &MetricsArgs) -> Result<Option<Metrics>, anyhow::Error> {
+        let addr = match args.metrics_addr {
+            Some(addr) => addr,
+            None => return Ok(None),
+        };
+
+        let listener = TcpListener::bind(addr)
+            .with_context(|| format!("Can't listen for metrics requests on {}", addr))?;
+        println!("Serving metrics on http://{}/metrics", addr);
+
+        let metrics = Metrics(Arc::new(Mutex::new(State::default())));
+        let metrics_clone = metrics.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(err) => {
+                        println!("Failed to accept metrics connection: {:?}", err);
+                        continue;
+                    }
+                };
+
+                // A thread per connection so that slow clients don't block the others
+                let metrics = metrics_clone.clone();
+                thread::spawn(move || {
+                    if let Err(err) = metrics.handle_request(stream) {
+                        println!("Failed to handle metrics request: {:?}", err);
+                    }
+                });
+            }
+        });
+
+        Ok(Some(metrics))
+    }
+
+    // Collect the statistics and the connection state of the webrtcbin of a peer until it's
+    // destroyed or the peer is removed
+    pub fn watch(&self, webrtcbin: &gst::Element, poller: &StatsPoller, peer: &str) {
+        self.0.lock().unwrap().peers.insert(
+            peer.to_string(),
+            PeerMetrics {
+                connection_state: WebRTCPeerConnectionState::New,
+                stats: Vec::new(),
+                bitrates: BTreeMap::new(),
+                frames_decoded: 0,
+                bin_path: bin_path(webrtcbin),
+            },
+        );
+
+        let metrics = self.clone();
+        let peer_clone = peer.to_string();
+        webrtcbin.connect_notify(Some("connection-state"), move |webrtcbin, _pspec| {
+            let connection_state = webrtcbin
+                .get_property("connection-state")
+                .unwrap()
+                .get_some::<WebRTCPeerConnectionState>()
+                .unwrap();
+
+            if let Some(peer) = metrics.0.lock().unwrap().peers.get_mut(&peer_clone) {
+                peer.connection_state = connection_state;
+            }
+        });
+
+        let metrics = self.clone();
+        let peer = peer.to_string();
+        poller.add(POLL_INTERVAL, move |stats| {
+            if let Some(peer) = metrics.0.lock().unwrap().peers.get_mut(&peer) {
+                peer.update_stats(stats);
+            }
+        });
+    }
+
+    // Count the decoded video frames of the peer that pass through the pad
+    pub fn count_decoded_frames(&self, pad: &gst::Pad, peer: &str) {
+        let metrics = self.clone();
+        let peer = peer.to_string();
+        pad.add_probe(gst::PadProbeType::BUFFER, move |_pad, _info| {
+            if let Some(peer) = metrics.0.lock().unwrap().peers.get_mut(&peer) {
+                peer.frames_decoded += 1;
+            }
+
+            gst::PadProbeReturn::Ok
+        })
+        .unwrap();
+    }
+
+    // Forget the peer and the dropped frames of the elements in its bin
+    pub fn remove_peer(&self, peer: &str) {
+        let mut state = self.0.lock().unwrap();
+        let bin_path = match state.peers.remove(peer) {
+            Some(PeerMetrics {
+                bin_path: Some(bin_path),
+                ..
+            }) => bin_path,
+            _ => return,
+        };
+
+        let prefix = format!("{}/", bin_path);
+        state
+            .frames_dropped
+            .retain(|element, _| !element.starts_with(&prefix));
+    }
+
+    // Count errors, warnings and dropped frames from the pipeline bus
+    pub fn handle_bus_message(&self, message: &gst::Message) {
+        use gst::message::MessageView;
+
+        let mut state = self.0.lock().unwrap();
+        match message.view() {
+            MessageView::Error(_) => state.bus_errors += 1,
+            MessageView::Warning(_) => state.bus_warnings += 1,
+            MessageView::Qos(qos) => {
+                let (_processed, dropped) = qos.get_stats();
+                let element = message
+                    .get_src()
+                    .map(|s| String::from(s.get_path_string()))
+                    .unwrap_or_else(|| String::from("None"));
+                // -1 if the element doesn't know
+                let dropped = dropped.get_value();
+                if dropped >= 0 {
+                    state.frames_dropped.insert(element, dropped as u64);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn handle_request(&self, stream: TcpStream) -> Result<(), anyhow::Error> {
+        stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+        stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+
+        let mut reader = BufReader::new(stream);
+
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line)?;
+
+        // Skip the headers, we don't need any of them
+        let mut header = String::new();
+        while reader.read_line(&mut header)? > 2 {
+            header.clear();
+        }
+
+        let (status, body) = self.response(&request_line);
+
+        let mut stream = reader.into_inner();
+        write!(
+            stream,
+            "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        )?;
+
+        Ok(())
+    }
+
+    // Status and body of the response to the HTTP request line
+    fn response(&self, request_line: &str) -> (&'static str, String) {
+        match request_line.split(' ').take(2).collect::<Vec<_>>()[..] {
+            ["GET", "/metrics"] => ("200 OK", self.render()),
+            ["GET", _] => ("404 Not Found", String::from("Not Found\n")),
+            _ => (
+                "405 Method Not Allowed",
+                String::from("Method Not Allowed\n"),
+            ),
+        }
+    }
+
+    // Render all metrics in the Prometheus text format
+    fn render(&self) -> String {
+        let state = self.0.lock().unwrap();
+        let mut families = Families::default();
+
+        families.add(
+            "webrtc_peers",
+            "gauge",
+            "Number of peers",
+            String::new(),
+            state.peers.len() as f64,
+        );
+
+        for (peer, metrics) in &state.peers {
+            for (connection_state, name) in CONNECTION_STATES.iter() {
+                families.add(
+                    "webrtc_connection_state",
+                    "gauge",
+                    "Whether the peer connection is in the state",
+                    format!("peer=\"{}\",state=\"{}\"", peer, name),
+                    (metrics.connection_state == *connection_state) as u8 as f64,
+                );
+            }
+
+            families.add(
+                "webrtc_frames_decoded_total",
+                "counter",
+                "Video frames decoded",
+                format!("peer=\"{}\"", peer),
+                metrics.frames_decoded as f64,
+            );
+
+            for stats in &metrics.stats {
+                metrics.render_stats(&mut families, peer, stats);
+            }
+        }
+
+        for (element, dropped) in &state.frames_dropped {
+            families.add(
+                "webrtc_frames_dropped_total",
+                "counter",
+                "Frames dropped according to the QoS messages of the element",
+                format!("element=\"{}\"", element.replace('"', "\\\"")),
+                *dropped as f64,
+            );
+        }
+
+        for (type_, count) in &[("error", state.bus_errors), ("warning", state.bus_warnings)] {
+            families.add(
+                "webrtc_bus_messages_total",
+                "counter",
+                "Errors and warnings posted on the pipeline bus",
+                format!("type=\"{}\"", type_),
+                *count as f64,
+            );
+        }
+
+        families.render()
+    }
+}
+
+// Path of the bin containing the webrtcbin, unless that's the pipeline with all other elements
+fn bin_path(webrtcbin: &gst::Element) -> Option<String> {
+    let bin = webrtcbin.get_parent()?;
+    bin.get_parent()?;
+
+    Some(String::from(bin.get_path_string()))
+}
+
+impl PeerMetrics {
+    fn update_stats(&mut self, stats: Vec<Stats>) {
+        fn bytes(stats: &Stats) -> Option<u64> {
+            match stats.kind {
+                StatsKind::InboundRtp(ref inbound) => inbound.bytes_received,
+                StatsKind::OutboundRtp(ref outbound) => outbound.bytes_sent,
+                _ => None,
+            }
+        }
+
+        for new in &stats {
+            let old = self.stats.iter().find(|old| old.id == new.id);
+            if let (Some(old), Some(new_bytes)) = (old, bytes(new)) {
+                let old_bytes = bytes(old).unwrap_or(0);
+                let duration = (new.timestamp - old.timestamp) / 1000.0;
+                if duration > 0.0 && new_bytes >= old_bytes {
+                    self.bitrates.insert(
+                        new.id.clone(),
+                        (new_bytes - old_bytes) as f64 * 8.0 / duration,
+                    );
+                }
+            }
+        }
+
+        self.stats = stats;
+    }
+
+    fn render_stats(&self, families: &mut Families, peer: &str, stats: &Stats) {
+        let labels = |direction: &str, ssrc: Option<u64>| {
+            format!(
+                "peer=\"{}\",direction=\"{}\",ssrc=\"{}\"",
+                peer,
+                direction,
+                ssrc.unwrap_or(0)
+            )
+        };
+
+        match stats.kind {
+            StatsKind::InboundRtp(ref inbound) => {
+                let labels = labels("inbound", inbound.ssrc);
+                families.add_opt(
+                    "webrtc_bitrate_bits_per_second",
+                    "gauge",
+                    "Bitrate of the RTP stream",
+                    &labels,
+                    self.bitrates.get(&stats.id).copied(),
+                );
+                families.add_opt(
+                    "webrtc_packets_lost",
+                    "gauge",
+                    "Packets of the RTP stream that were lost",
+                    &labels,
+                    inbound.packets_lost.map(|lost| lost as f64),
+                );
+                families.add_opt(
+                    "webrtc_jitter_seconds",
+                    "gauge",
+                    "Interarrival jitter of the RTP stream",
+                    &labels,
+                    inbound.jitter,
+                );
+                families.add_opt(
+                    "webrtc_nack_count_total",
+                    "counter",
+                    "NACKs sent for the inbound or received for the outbound RTP stream",
+                    &labels,
+                    inbound.nack_count.map(|count| count as f64),
+                );
+                families.add_opt(
+                    "webrtc_pli_count_total",
+                    "counter",
+                    "PLIs sent for the inbound or received for the outbound RTP stream",
+                    &labels,
+                    inbound.pli_count.map(|count| count as f64),
+                );
+            }
+            StatsKind::OutboundRtp(ref outbound) => {
+                let labels = labels("outbound", outbound.ssrc);
+                families.add_opt(
+                    "webrtc_bitrate_bits_per_second",
+                    "gauge",
+                    "Bitrate of the RTP stream",
+                    &labels,
+                    self.bitrates.get(&stats.id).copied(),
+                );
+                families.add_opt(
+                    "webrtc_nack_count_total",
+                    "counter",
+                    "NACKs sent for the inbound or received for the outbound RTP stream",
+                    &labels,
+                    outbound.nack_count.map(|count| count as f64),
+                );
+                families.add_opt(
+                    "webrtc_pli_count_total",
+                    "counter",
+                    "PLIs sent for the inbound or received for the outbound RTP stream",
+                    &labels,
+                    outbound.pli_count.map(|count| count as f64),
+                );
+            }
+            // The outbound streams as seen by the peer
+            StatsKind::RemoteInboundRtp(ref remote) => {
+                let labels = labels("outbound", remote.ssrc);
+                families.add_opt(
+                    "webrtc_packets_lost",
+                    "gauge",
+                    "Packets of the RTP stream that were lost",
+                    &labels,
+                    remote.packets_lost.map(|lost| lost as f64),
+                );
+                families.add_opt(
+                    "webrtc_jitter_seconds",
+                    "gauge",
+                    "Interarrival jitter of the RTP stream",
+                    &labels,
+                    remote.jitter,
+                );
+                families.add_opt(
+                    "webrtc_round_trip_time_seconds",
+                    "gauge",
+                    "Round trip time according to the RTCP reports of the peer",
+                    &labels,
+                    remote.round_trip_time,
+                );
+            }
+            _ => (),
+        }
+    }
+}
+
+// Samples grouped by metric name, as all samples of a metric have to follow its HELP and TYPE
+#[derive(Default)]
+struct Families(BTreeMap<&'static str, (&'static str, &'static str, Vec<(String, f64)>)>);
+
+impl Families {
+    fn add(
+        &mut self,
+        name: &'static str,
+        type_: &'static str,
+        help: &'static str,
+        labels: String,
+        value: f64,
+    ) {
+        self.0
+            .entry(name)
+            .or_insert_with(|| (type_, help, Vec::new()))
+            .2
+            .push((labels, value));
+    }
+
+    fn add_opt(
+        &mut self,
+        name: &'static str,
+        type_: &'static str,
+        help: &'static str,
+        labels: &str,
+        value: Option<f64>,
+    ) {
+        if let Some(value) = value {
+            self.add(name, type_, help, labels.to_string(), value);
+        }
+    }
+
+    fn render(&self) -> String {
+        let mut output = String::new();
+
+        for (name, (type_, help, samples)) in &self.0 {
+            writeln!(output, "# HELP {} {}", name, help).unwrap();
+            writeln!(output, "# TYPE {} {}", name, type_).unwrap();
+            for (labels, value) in samples {
+                if labels.is_empty() {
+                    writeln!(output, "{} {}", name, value).unwrap();
+                } else {
+                    writeln!(output, "{}{{{}}} {}", name, labels, value).unwrap();
+                }
+            }
+        }
+
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::stats::InboundRtp;
+
+    fn inbound(timestamp: f64, bytes_received: u64) -> Stats {
+        Stats {
+            id: "inbound-rtp_1234".into(),
+            timestamp,
+            kind: StatsKind::InboundRtp(InboundRtp {
+                ssrc: Some(1234),
+                packets_received: None,
+                bytes_received: Some(bytes_received),
+                packets_lost: Some(3),
+                jitter: Some(0.01),
+                nack_count: None,
+                pli_count: None,
+                fir_count: None,
+                frames_decoded: None,
+                frames_dropped: None,
+            }),
+        }
+    }
+
+    fn metrics() -> Metrics {
+        let mut peer = PeerMetrics {
+            connection_state: WebRTCPeerConnectionState::Connected,
+            stats: Vec::new(),
+            bitrates: BTreeMap::new(),
+            frames_decoded: 10,
+            bin_path: Some(String::from("/pipeline0/bin-1")),
+        };
+        peer.update_stats(vec![inbound(1000.0, 0)]);
+        peer.update_stats(vec![inbound(2000.0, 125_000)]);
+
+        let mut state = State::default();
+        state.peers.insert(String::from("1"), peer);
+        state.bus_errors = 2;
+        state
+            .frames_dropped
+            .insert(String::from("/pipeline0/bin-1/videoconvert0"), 5);
+        state
+            .frames_dropped
+            .insert(String::from("/pipeline0/videomixer0"), 1);
+
+        Metrics(Arc::new(Mutex::new(state)))
+    }
+
+    #[test]
+    fn render() {
+        let output = metrics().render();
+
+        for expected in &[
+            "# HELP webrtc_peers Number of peers\n# TYPE webrtc_peers gauge\nwebrtc_peers 1\n",
+            "webrtc_connection_state{peer=\"1\",state=\"new\"} 0\n",
+            "webrtc_connection_state{peer=\"1\",state=\"connected\"} 1\n",
+            "webrtc_frames_decoded_total{peer=\"1\"} 10\n",
+            "webrtc_bitrate_bits_per_second{peer=\"1\",direction=\"inbound\",ssrc=\"1234\"} 1000000\n",
+            "webrtc_packets_lost{peer=\"1\",direction=\"inbound\",ssrc=\"1234\"} 3\n",
+            "webrtc_jitter_seconds{peer=\"1\",direction=\"inbound\",ssrc=\"1234\"} 0.01\n",
+            "webrtc_frames_dropped_total{element=\"/pipeline0/bin-1/videoconvert0\"} 5\n",
+            "webrtc_bus_messages_total{type=\"error\"} 2\n",
+            "webrtc_bus_messages_total{type=\"warning\"} 0\n",
+        ] {
+            assert!(output.contains(expected), "{} not in\n{}", expected, output);
+        }
+
+        // Each family has its HELP and TYPE only once
+        assert_eq!(output.matches("# TYPE webrtc_connection_state").count(), 1);
+        assert!(!output.contains("webrtc_round_trip_time_seconds"));
+    }
+
+    #[test]
+    fn remove_peer() {
+        let metrics = metrics();
+        metrics.remove_peer("1");
+
+        let output = metrics.render();
+        assert!(output.contains("webrtc_peers 0\n"));
+        assert!(!output.contains("peer=\"1\""));
+        assert!(!output.contains("bin-1"));
+        assert!(
+            output.contains("webrtc_frames_dropped_total{element=\"/pipeline0/videomixer0\"} 1\n")
+        );
+    }
+
+    #[test]
+    fn routing() {
+        let metrics = metrics();
+
+        let (status, body) = metrics.response("GET /metrics HTTP/1.1\r\n");
+        assert_eq!(status, "200 OK");
+        assert_eq!(body, metrics.render());
+
+        assert_eq!(
+            metrics.response("GET / HTTP/1.1\r\n"),
+            ("404 Not Found", String::from("Not Found\n"))
+        );
+        assert_eq!(
+            metrics.response("POST /metrics HTTP/1.1\r\n"),
+            (
+                "405 Method Not Allowed",
+                String::from("Method Not Allowed\n")
+            )
+        );
+        assert_eq!(
+            metrics.response(""),
+            (
+                "405 Method Not Allowed",
+                String::from("Method Not Allowed\n")
+            )
+        );
+    }
+}
@@ -0,0 +1,236 @@
+// Packet loss recovery with retransmissions and forward error correction
+//
+// Both are configured per transceiver of webrtcbin, which then negotiates the additional RTX, RED
+// and ULPFEC payload types and the NACK feedback with the peer.
+
+use structopt::StructOpt;
+
+use gst::prelude::*;
+
+use anyhow::{bail, Context};
+
+// Which forward error correction webrtcbin adds to the sent streams
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FecType {
+    None,
+    UlpRed,
+}
+
+impl FecType {
+    // Value of the transceiver fec-type property
+    fn as_str(self) -> &'static str {
+        match self {
+            FecType::None => "none",
+            FecType::UlpRed => "ulp-red",
+        }
+    }
+}
+
+impl std::str::FromStr for FecType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(FecType::None),
+            "ulp-red" => Ok(FecType::UlpRed),
+            _ => bail!("Invalid FEC type: {}. Use either none or ulp-red", s),
+        }
+    }
+}
+
+// Command line arguments for the packet loss recovery, to be flattened into the Args of each demo
+#[derive(Debug, Clone, StructOpt)]
+pub struct RecoveryArgs {
+    // Request lost packets with NACKs and retransmit them on a separate RTX stream. webrtcbin
+    // always enables both together, so --rtx is the same
+    #[structopt(long, visible_alias = "rtx")]
+    pub nack: bool,
+    #[structopt(long, default_value = "none")]
+    pub fec_type: FecType,
+    // Number of FEC packets in percent of the media packets
+    #[structopt(long, default_value = "10")]
+    pub fec_percentage: u32,
+}
+
+// Whether one media of an SDP has a payload type with the given encoding, e.g. rtx
+fn has_encoding(media: &gst_sdp::SDPMediaRef, encoding: &str) -> bool {
+    (0..)
+        .map(|nth| media.get_attribute_val_n("rtpmap", nth))
+        .take_while(Option::is_some)
+        .flatten()
+        .filter_map(|rtpmap| rtpmap.split(' ').nth(1))
+        .filter_map(|encoding_name| encoding_name.split('/').next())
+        .any(|name| name.eq_ignore_ascii_case(encoding))
+}
+
+// Whether one media of an SDP has a plain NACK feedback, i.e. without the pli parameter
+fn has_nack(media: &gst_sdp::SDPMediaRef) -> bool {
+    (0..)
+        .map(|nth| media.get_attribute_val_n("rtcp-fb", nth))
+        .take_while(Option::is_some)
+        .flatten()
+        .any(|rtcp_fb| rtcp_fb.split(' ').skip(1).eq(std::iter::once("nack")))
+}
+
+impl RecoveryArgs {
+    // Configure a transceiver of webrtcbin before the SDP is created for it
+    pub fn configure_transceiver(
+        &self,
+        transceiver: &gst_webrtc::WebRTCRTPTransceiver,
+    ) -> Result<(), anyhow::Error> {
+        transceiver
+            .set_property("do-nack", &self.nack)
+            .context("Can't configure NACKs")?;
+        transceiver.set_property_from_str("fec-type", self.fec_type.as_str());
+        if self.fec_type != FecType::None {
+            transceiver
+                .set_property("fec-percentage", &self.fec_percentage)
+                .context("Can't configure FEC percentage")?;
+        }
+
+        Ok(())
+    }
+
+    // Check that everything configured is used for all audio and video in the negotiated SDP, as
+    // the peer might not support it
+    pub fn verify_sdp(&self, sdp: &gst_sdp::SDPMessageRef) -> Result<(), anyhow::Error> {
+        let mut missing = Vec::new();
+
+        for idx in 0..sdp.medias_len() {
+            let media = sdp.get_media(idx).unwrap();
+            let kind = media.get_media().unwrap_or_default();
+            // Rejected media have port 0
+            if !["audio", "video"].contains(&kind) || media.get_port() == 0 {
+                continue;
+            }
+
+            if self.nack {
+                if !has_nack(media) {
+                    missing.push(format!("NACK for {} {}", kind, idx));
+                }
+                if !has_encoding(media, "rtx") {
+                    missing.push(format!("RTX for {} {}", kind, idx));
+                }
+            }
+            if self.fec_type == FecType::UlpRed {
+                if !has_encoding(media, "red") {
+                    missing.push(format!("RED for {} {}", kind, idx));
+                }
+                if !has_encoding(media, "ulpfec") {
+                    missing.push(format!("ULPFEC for {} {}", kind, idx));
+                }
+            }
+        }
+
+        if !missing.is_empty() {
+            bail!("Not negotiated: {}", missing.join(", "));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Answer with NACK, RTX, RED and ULPFEC for the video but only NACK for the audio, a rejected
+    // video and a data channel
+    const ANSWER: &str = concat!(
+        "v=0\r\n",
+        "o=- 0 0 IN IP4 0.0.0.0\r\n",
+        "s=-\r\n",
+        "t=0 0\r\n",
+        "m=audio 9 UDP/TLS/RTP/SAVPF 111\r\n",
+        "a=rtpmap:111 OPUS/48000/2\r\n",
+        "a=rtcp-fb:111 nack\r\n",
+        "m=video 9 UDP/TLS/RTP/SAVPF 96 97 98 99\r\n",
+        "a=rtpmap:96 VP8/90000\r\n",
+        "a=rtcp-fb:96 nack pli\r\n",
+        "a=rtcp-fb:96 nack\r\n",
+        "a=rtpmap:97 RTX/90000\r\n",
+        "a=fmtp:97 apt=96\r\n",
+        "a=rtpmap:98 red/90000\r\n",
+        "a=rtpmap:99 ulpfec/90000\r\n",
+        "m=video 0 UDP/TLS/RTP/SAVPF 96\r\n",
+        "a=rtpmap:96 VP8/90000\r\n",
+        "m=application 9 UDP/DTLS/SCTP webrtc-datachannel\r\n",
+    );
+
+    fn answer() -> gst_sdp::SDPMessage {
+        gst_sdp::SDPMessage::parse_buffer(ANSWER.as_bytes()).unwrap()
+    }
+
+    fn args(nack: bool, fec_type: FecType) -> RecoveryArgs {
+        RecoveryArgs {
+            nack,
+            fec_type,
+            fec_percentage: 10,
+        }
+    }
+
+    #[test]
+    fn parse_fec_types() {
+        assert_eq!("none".parse::<FecType>().unwrap(), FecType::None);
+        assert_eq!("ulp-red".parse::<FecType>().unwrap(), FecType::UlpRed);
+        assert!("ulpfec".parse::<FecType>().is_err());
+        assert!("".parse::<FecType>().is_err());
+    }
+
+    #[test]
+    fn find_encodings_and_nack() {
+        let sdp = answer();
+        let audio = sdp.get_media(0).unwrap();
+        let video = sdp.get_media(1).unwrap();
+        let rejected = sdp.get_media(2).unwrap();
+
+        assert!(has_nack(audio));
+        assert!(!has_encoding(audio, "rtx"));
+        assert!(has_nack(video));
+        assert!(has_encoding(video, "rtx"));
+        assert!(has_encoding(video, "RED"));
+        assert!(has_encoding(video, "ulpfec"));
+        assert!(!has_encoding(video, "vp9"));
+
+        // nack pli only requests keyframes
+        let sdp = gst_sdp::SDPMessage::parse_buffer(
+            concat!(
+                "v=0\r\n",
+                "o=- 0 0 IN IP4 0.0.0.0\r\n",
+                "s=-\r\n",
+                "t=0 0\r\n",
+                "m=video 9 UDP/TLS/RTP/SAVPF 96\r\n",
+                "a=rtpmap:96 VP8/90000\r\n",
+                "a=rtcp-fb:96 nack pli\r\n",
+            )
+            .as_bytes(),
+        )
+        .unwrap();
+        assert!(!has_nack(sdp.get_media(0).unwrap()));
+        assert!(!has_nack(rejected));
+    }
+
+    #[test]
+    fn verify_negotiated_sdp() {
+        let sdp = answer();
+
+        // Nothing configured needs nothing
+        args(false, FecType::None).verify_sdp(&sdp).unwrap();
+
+        // The rejected video and the data channel are not checked
+        assert_eq!(
+            args(true, FecType::None)
+                .verify_sdp(&sdp)
+                .unwrap_err()
+                .to_string(),
+            "Not negotiated: RTX for audio 0"
+        );
+        assert_eq!(
+            args(false, FecType::UlpRed)
+                .verify_sdp(&sdp)
+                .unwrap_err()
+                .to_string(),
+            "Not negotiated: RED for audio 0, ULPFEC for audio 0"
+        );
+    }
+}
@@ -0,0 +1,607 @@
+// Periodic reporting of the webrtcbin statistics
+//
+// The statistics returned by the get-stats action signal are parsed into the typed structs below
+// and written as one JSON object per line, either to stdout or to a file.
+
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use async_std::task;
+
+use structopt::StructOpt;
+
+use gst::prelude::*;
+use gst_webrtc::WebRTCStatsType;
+
+use serde_derive::Serialize;
+
+use anyhow::Context;
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct InboundRtp {
+    pub ssrc: Option<u64>,
+    pub packets_received: Option<u64>,
+    pub bytes_received: Option<u64>,
+    pub packets_lost: Option<i64>,
+    // In seconds
+    pub jitter: Option<f64>,
+    pub nack_count: Option<u64>,
+    pub pli_count: Option<u64>,
+    pub fir_count: Option<u64>,
+    pub frames_decoded: Option<u64>,
+    pub frames_dropped: Option<u64>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct OutboundRtp {
+    pub ssrc: Option<u64>,
+    pub packets_sent: Option<u64>,
+    pub bytes_sent: Option<u64>,
+    pub nack_count: Option<u64>,
+    pub pli_count: Option<u64>,
+    pub fir_count: Option<u64>,
+}
+
+// What the peer reported about the streams we send in its RTCP receiver reports
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct RemoteInboundRtp {
+    pub ssrc: Option<u64>,
+    pub packets_lost: Option<i64>,
+    pub fraction_lost: Option<f64>,
+    // In seconds
+    pub jitter: Option<f64>,
+    // In seconds
+    pub round_trip_time: Option<f64>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct CandidatePair {
+    pub local_candidate_id: Option<String>,
+    pub remote_candidate_id: Option<String>,
+    pub nominated: Option<bool>,
+    pub bytes_sent: Option<u64>,
+    pub bytes_received: Option<u64>,
+    // In seconds
+    pub current_round_trip_time: Option<f64>,
+    // In bits per second
+    pub available_outgoing_bitrate: Option<f64>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct Transport {
+    pub bytes_sent: Option<u64>,
+    pub bytes_received: Option<u64>,
+    pub selected_candidate_pair_id: Option<String>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum StatsKind {
+    InboundRtp(InboundRtp),
+    OutboundRtp(OutboundRtp),
+    RemoteInboundRtp(RemoteInboundRtp),
+    CandidatePair(CandidatePair),
+    Transport(Transport),
+}
+
+// One entry of a statistics report
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct Stats {
+    pub id: String,
+    // In milliseconds
+    pub timestamp: f64,
+    #[serde(flatten)]
+    pub kind: StatsKind,
+}
+
+// The integer types of the fields differ between the kinds of statistics and GStreamer versions
+fn uint(s: &gst::StructureRef, name: &str) -> Option<u64> {
+    s.get_some::<u64>(name)
+        .ok()
+        .or_else(|| s.get_some::<u32>(name).ok().map(u64::from))
+}
+
+fn int(s: &gst::StructureRef, name: &str) -> Option<i64> {
+    s.get_some::<i64>(name)
+        .ok()
+        .or_else(|| s.get_some::<i32>(name).ok().map(i64::from))
+}
+
+fn double(s: &gst::StructureRef, name: &str) -> Option<f64> {
+    s.get_some::<f64>(name).ok()
+}
+
+fn string(s: &gst::StructureRef, name: &str) -> Option<String> {
+    s.get::<String>(name).ok().flatten()
+}
+
+impl CandidatePair {
+    fn parse(s: &gst::StructureRef) -> CandidatePair {
+        CandidatePair {
+            local_candidate_id: string(s, "local-candidate-id"),
+            remote_candidate_id: string(s, "remote-candidate-id"),
+            nominated: s.get_some::<bool>("nominated").ok(),
+            bytes_sent: uint(s, "bytes-sent"),
+            bytes_received: uint(s, "bytes-received"),
+            current_round_trip_time: double(s, "current-round-trip-time"),
+            available_outgoing_bitrate: double(s, "available-outgoing-bitrate"),
+        }
+    }
+}
+
+impl Stats {
+    // Parse one entry of the get-stats reply, or return None for kinds we don't report
+    fn from_structure(s: &gst::StructureRef) -> Option<Stats> {
+        let kind = match s.get_some::<WebRTCStatsType>("type").ok()? {
+            WebRTCStatsType::InboundRtp => StatsKind::InboundRtp(InboundRtp {
+                ssrc: uint(s, "ssrc"),
+                packets_received: uint(s, "packets-received"),
+                bytes_received: uint(s, "bytes-received"),
+                packets_lost: int(s, "packets-lost"),
+                jitter: double(s, "jitter"),
+                nack_count: uint(s, "nack-count"),
+                pli_count: uint(s, "pli-count"),
+                fir_count: uint(s, "fir-count"),
+                frames_decoded: uint(s, "frames-decoded"),
+                frames_dropped: uint(s, "frames-dropped"),
+            }),
+            WebRTCStatsType::OutboundRtp => StatsKind::OutboundRtp(OutboundRtp {
+                ssrc: uint(s, "ssrc"),
+                packets_sent: uint(s, "packets-sent"),
+                bytes_sent: uint(s, "bytes-sent"),
+                nack_count: uint(s, "nack-count"),
+                pli_count: uint(s, "pli-count"),
+                fir_count: uint(s, "fir-count"),
+            }),
+            WebRTCStatsType::RemoteInboundRtp => StatsKind::RemoteInboundRtp(RemoteInboundRtp {
+                ssrc: uint(s, "ssrc"),
+                packets_lost: int(s, "packets-lost"),
+                fraction_lost: double(s, "fraction-lost"),
+                jitter: double(s, "jitter"),
+                round_trip_time: double(s, "round-trip-time"),
+            }),
+            WebRTCStatsType::CandidatePair => StatsKind::CandidatePair(CandidatePair::parse(s)),
+            // Older webrtcbin versions report candidate pairs with the transport type, but their
+            // structure is named after their id
+            WebRTCStatsType::Transport if s.get_name().starts_with("ice-candidate-pair") => {
+                StatsKind::CandidatePair(CandidatePair::parse(s))
+            }
+            WebRTCStatsType::Transport => StatsKind::Transport(Transport {
+                bytes_sent: uint(s, "bytes-sent"),
+                bytes_received: uint(s, "bytes-received"),
+                selected_candidate_pair_id: string(s, "selected-candidate-pair-id"),
+            }),
+            _ => return None,
+        };
+
+        Some(Stats {
+            id: string(s, "id").unwrap_or_else(|| s.get_name().to_string()),
+            timestamp: double(s, "timestamp").unwrap_or(0.0),
+            kind,
+        })
+    }
+}
+
+// Parse the reply of the webrtcbin get-stats action signal
+pub fn parse_stats(reply: &gst::StructureRef) -> Vec<Stats> {
+    reply
+        .iter()
+        .filter_map(|(_name, value)| value.get::<gst::Structure>().ok().flatten())
+        .filter_map(|s| Stats::from_structure(&s))
+        .collect()
+}
+
+// Request the statistics of the webrtcbin once and pass them to the function
+pub fn request_stats<F>(webrtcbin: &gst::Element, func: F)
+where
+    F: FnOnce(Vec<Stats>) + Send + 'static,
+{
+    let promise = gst::Promise::with_change_func(move |reply| {
+        if let Ok(Some(reply)) = reply {
+            func(parse_stats(reply));
+        }
+    });
+
+    webrtcbin
+        .emit("get-stats", &[&None::<gst::Pad>, &promise])
+        .unwrap();
+}
+
+// Time between two polls of a StatsPoller. The intervals of its consumers are multiples of this
+const POLL_TICK: Duration = Duration::from_secs(1);
+
+type StatsFunc = Arc<dyn Fn(Vec<Stats>) + Send + Sync>;
+
+// A consumer of the statistics that wants them every `period` ticks
+struct Consumer {
+    period: u64,
+    func: StatsFunc,
+}
+
+struct StatsPollerInner {
+    consumers: Mutex<Vec<Consumer>>,
+}
+
+impl StatsPollerInner {
+    // The consumers that want the statistics at this tick
+    fn due(&self, tick: u64) -> Vec<StatsFunc> {
+        self.consumers
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|consumer| tick % consumer.period == 0)
+            .map(|consumer| consumer.func.clone())
+            .collect()
+    }
+}
+
+// Polls the statistics of one webrtcbin for all its consumers, e.g. the statistics output, the
+// metrics and the overlay. A single task requests them once per tick if any consumer is due and
+// passes them to each of those, until the webrtcbin is destroyed
+#[derive(Clone)]
+pub struct StatsPoller(Arc<StatsPollerInner>);
+
+impl std::fmt::Debug for StatsPoller {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("StatsPoller")
+            .field("consumers", &self.0.consumers.lock().unwrap().len())
+            .finish()
+    }
+}
+
+impl StatsPoller {
+    pub fn new(webrtcbin: &gst::Element) -> StatsPoller {
+        let poller = StatsPoller(Arc::new(StatsPollerInner {
+            consumers: Mutex::new(Vec::new()),
+        }));
+
+        let inner = poller.0.clone();
+        let webrtcbin = webrtcbin.downgrade();
+        task::spawn(async move {
+            let mut tick = 0u64;
+            loop {
+                task::sleep(POLL_TICK).await;
+                tick += 1;
+
+                let webrtcbin = match webrtcbin.upgrade() {
+                    Some(webrtcbin) => webrtcbin,
+                    None => break,
+                };
+
+                let due = inner.due(tick);
+                if due.is_empty() {
+                    continue;
+                }
+
+                request_stats(&webrtcbin, move |stats| {
+                    for func in due {
+                        func(stats.clone());
+                    }
+                });
+            }
+        });
+
+        poller
+    }
+
+    // Pass the statistics to the function every interval, rounded to whole seconds
+    pub fn add<F>(&self, interval: Duration, func: F)
+    where
+        F: Fn(Vec<Stats>) + Send + Sync + 'static,
+    {
+        let period = (interval.as_secs_f64() / POLL_TICK.as_secs_f64()).round() as u64;
+
+        self.0.consumers.lock().unwrap().push(Consumer {
+            period: period.max(1),
+            func: Arc::new(func),
+        });
+    }
+}
+
+// Command line arguments for the statistics reports, to be flattened into the Args of each demo
+#[derive(Debug, Clone, StructOpt)]
+pub struct StatsArgs {
+    // Report the statistics every given number of seconds
+    #[structopt(long)]
+    pub stats_interval: Option<u64>,
+    // Write the statistics to this file instead of stdout
+    #[structopt(long, parse(from_os_str), requires = "stats-interval")]
+    pub stats_file: Option<PathBuf>,
+    // Only include these fields, e.g. --stats-fields=packets-lost,jitter
+    #[structopt(long, use_delimiter = true, requires = "stats-interval")]
+    pub stats_fields: Vec<String>,
+}
+
+// The JSON line for one entry, with only the given fields unless there are none
+fn json_line(
+    stats: &Stats,
+    fields: &[String],
+    peer_id: Option<u32>,
+) -> Result<serde_json::Value, anyhow::Error> {
+    let mut line = match serde_json::to_value(stats)? {
+        serde_json::Value::Object(line) => line,
+        _ => unreachable!(),
+    };
+
+    // Missing fields are left out, and unless configured otherwise all others are kept
+    line.retain(|name, value| {
+        !value.is_null()
+            && (fields.is_empty()
+                || ["id", "timestamp", "type"].contains(&name.as_str())
+                || fields.contains(name))
+    });
+    if let Some(peer_id) = peer_id {
+        line.insert(String::from("peer-id"), peer_id.into());
+    }
+
+    Ok(serde_json::Value::Object(line))
+}
+
+struct StatsReporterInner {
+    interval: Duration,
+    fields: Vec<String>,
+    output: Mutex<Box<dyn Write + Send>>,
+}
+
+// Writes the statistics of any number of webrtcbins to the configured output
+#[derive(Clone)]
+pub struct StatsReporter(Arc<StatsReporterInner>);
+
+impl std::fmt::Debug for StatsReporter {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("StatsReporter")
+            .field("interval", &self.0.interval)
+            .field("fields", &self.0.fields)
+            .finish()
+    }
+}
+
+impl StatsReporter {
+    // Create the reporter if statistics were requested on the command line
+    pub fn new(args: &StatsArgs) -> Result<Option<StatsReporter>, anyhow::Error> {
+        let interval = match args.stats_interval {
+            Some(interval) => Duration::from_secs(interval.max(1)),
+            None => return Ok(None),
+        };
+
+        let output: Box<dyn Write + Send> = match args.stats_file {
+            Some(ref path) => Box::new(
+                File::create(path)
+                    .with_context(|| format!("Can't create file {}", path.display()))?,
+            ),
+            None => Box::new(io::stdout()),
+        };
+
+        Ok(Some(StatsReporter(Arc::new(StatsReporterInner {
+            interval,
+            fields: args.stats_fields.clone(),
+            output: Mutex::new(output),
+        }))))
+    }
+
+    // Periodically write the statistics of the poller's webrtcbin until it's destroyed. Each
+    // entry is tagged with the peer id, if any
+    pub fn watch(&self, poller: &StatsPoller, peer_id: Option<u32>) {
+        let reporter = self.clone();
+        poller.add(self.0.interval, move |stats| {
+            if let Err(err) = reporter.write(peer_id, &stats) {
+                println!("Failed to write statistics: {:?}", err);
+            }
+        });
+    }
+
+    // Write an event of the application, like a change of the active speaker, as a line of the
+    // given type with the additional fields. Its timestamp is in milliseconds since the Unix epoch
+    pub fn write_event(
+        &self,
+        type_: &str,
+        peer_id: Option<u32>,
+        fields: serde_json::Map<String, serde_json::Value>,
+    ) -> Result<(), anyhow::Error> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|since_epoch| since_epoch.as_secs_f64() * 1000.0)
+            .unwrap_or(0.0);
+
+        let mut line = fields;
+        line.insert(String::from("type"), type_.into());
+        line.insert(String::from("timestamp"), timestamp.into());
+        if let Some(peer_id) = peer_id {
+            line.insert(String::from("peer-id"), peer_id.into());
+        }
+
+        let mut output = self.0.output.lock().unwrap();
+        writeln!(output, "{}", serde_json::Value::Object(line))?;
+        output.flush()?;
+
+        Ok(())
+    }
+
+    fn write(&self, peer_id: Option<u32>, stats: &[Stats]) -> Result<(), anyhow::Error> {
+        let mut output = self.0.output.lock().unwrap();
+
+        for stats in stats {
+            writeln!(output, "{}", json_line(stats, &self.0.fields, peer_id)?)?;
+        }
+
+        output.flush()?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    #[test]
+    fn poller_due_consumers() {
+        let poller = StatsPoller(Arc::new(StatsPollerInner {
+            consumers: Mutex::new(Vec::new()),
+        }));
+        poller.add(Duration::from_secs(1), |_stats| ());
+        poller.add(Duration::from_secs(2), |_stats| ());
+        poller.add(Duration::from_secs(5), |_stats| ());
+        // Rounded up to one tick
+        poller.add(Duration::from_millis(200), |_stats| ());
+
+        assert_eq!(poller.0.due(1).len(), 2);
+        assert_eq!(poller.0.due(2).len(), 3);
+        assert_eq!(poller.0.due(5).len(), 3);
+        assert_eq!(poller.0.due(10).len(), 4);
+    }
+
+    // Reply of get-stats with the kinds we report, one we don't and a candidate pair as reported
+    // by older webrtcbin versions
+    fn reply() -> gst::Structure {
+        gst::init().unwrap();
+
+        let inbound = gst::Structure::builder("inbound-rtp_1234")
+            .field("type", &WebRTCStatsType::InboundRtp)
+            .field("id", &"inbound-rtp_1234")
+            .field("timestamp", &1000.0f64)
+            .field("ssrc", &1234u32)
+            .field("packets-received", &100u64)
+            .field("bytes-received", &20_000u64)
+            .field("packets-lost", &-1i32)
+            .field("jitter", &0.01f64)
+            .build();
+        let remote_inbound = gst::Structure::builder("rtp-remote-inbound-stream-stats_5678")
+            .field("type", &WebRTCStatsType::RemoteInboundRtp)
+            .field("id", &"rtp-remote-inbound-stream-stats_5678")
+            .field("timestamp", &1000.0f64)
+            .field("ssrc", &5678u32)
+            .field("packets-lost", &3i64)
+            .field("fraction-lost", &0.05f64)
+            .field("round-trip-time", &0.1f64)
+            .build();
+        let codec = gst::Structure::builder("codec-stats-96")
+            .field("type", &WebRTCStatsType::Codec)
+            .field("id", &"codec-stats-96")
+            .field("timestamp", &1000.0f64)
+            .build();
+        let old_pair = gst::Structure::builder("ice-candidate-pair_0")
+            .field("type", &WebRTCStatsType::Transport)
+            .field("timestamp", &1000.0f64)
+            .field("local-candidate-id", &"local_0")
+            .field("remote-candidate-id", &"remote_0")
+            .field("available-outgoing-bitrate", &500_000.0f64)
+            .build();
+        let transport = gst::Structure::builder("transport-stream_0")
+            .field("type", &WebRTCStatsType::Transport)
+            .field("id", &"transport-stream_0")
+            .field("timestamp", &1000.0f64)
+            .field("selected-candidate-pair-id", &"ice-candidate-pair_0")
+            .build();
+
+        gst::Structure::builder("application/x-webrtc-stats")
+            .field("inbound-rtp_1234", &inbound)
+            .field("rtp-remote-inbound-stream-stats_5678", &remote_inbound)
+            .field("codec-stats-96", &codec)
+            .field("ice-candidate-pair_0", &old_pair)
+            .field("transport-stream_0", &transport)
+            .build()
+    }
+
+    #[test]
+    fn parse_reply() {
+        let stats = parse_stats(&reply());
+
+        assert_eq!(
+            stats,
+            vec![
+                Stats {
+                    id: "inbound-rtp_1234".into(),
+                    timestamp: 1000.0,
+                    kind: StatsKind::InboundRtp(InboundRtp {
+                        ssrc: Some(1234),
+                        packets_received: Some(100),
+                        bytes_received: Some(20_000),
+                        packets_lost: Some(-1),
+                        jitter: Some(0.01),
+                        nack_count: None,
+                        pli_count: None,
+                        fir_count: None,
+                        frames_decoded: None,
+                        frames_dropped: None,
+                    }),
+                },
+                Stats {
+                    id: "rtp-remote-inbound-stream-stats_5678".into(),
+                    timestamp: 1000.0,
+                    kind: StatsKind::RemoteInboundRtp(RemoteInboundRtp {
+                        ssrc: Some(5678),
+                        packets_lost: Some(3),
+                        fraction_lost: Some(0.05),
+                        jitter: None,
+                        round_trip_time: Some(0.1),
+                    }),
+                },
+                Stats {
+                    id: "ice-candidate-pair_0".into(),
+                    timestamp: 1000.0,
+                    kind: StatsKind::CandidatePair(CandidatePair {
+                        local_candidate_id: Some("local_0".into()),
+                        remote_candidate_id: Some("remote_0".into()),
+                        nominated: None,
+                        bytes_sent: None,
+                        bytes_received: None,
+                        current_round_trip_time: None,
+                        available_outgoing_bitrate: Some(500_000.0),
+                    }),
+                },
+                Stats {
+                    id: "transport-stream_0".into(),
+                    timestamp: 1000.0,
+                    kind: StatsKind::Transport(Transport {
+                        bytes_sent: None,
+                        bytes_received: None,
+                        selected_candidate_pair_id: Some("ice-candidate-pair_0".into()),
+                    }),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn write_json_lines() {
+        let stats = parse_stats(&reply());
+
+        assert_eq!(
+            json_line(&stats[0], &[], None).unwrap(),
+            json!({
+                "id": "inbound-rtp_1234",
+                "timestamp": 1000.0,
+                "type": "inbound-rtp",
+                "ssrc": 1234,
+                "packets-received": 100,
+                "bytes-received": 20_000,
+                "packets-lost": -1,
+                "jitter": 0.01,
+            })
+        );
+
+        let fields = vec![String::from("packets-lost"), String::from("jitter")];
+        assert_eq!(
+            json_line(&stats[1], &fields, Some(42)).unwrap(),
+            json!({
+                "id": "rtp-remote-inbound-stream-stats_5678",
+                "timestamp": 1000.0,
+                "type": "remote-inbound-rtp",
+                "packets-lost": 3,
+                "peer-id": 42,
+            })
+        );
+    }
+}
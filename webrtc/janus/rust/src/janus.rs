@@ -24,7 +24,7 @@ use {
     async_tungstenite::{gio::connect_async, tungstenite},
     futures::channel::mpsc,
     futures::sink::{Sink, SinkExt},
-    futures::stream::{Stream, StreamExt},
+    futures::stream::{BoxStream, Stream, StreamExt},
     gst::gst_element_error,
     gst::prelude::*,
     http::Request,
@@ -34,6 +34,11 @@ use {
     std::sync::{Arc, Mutex, Weak},
     structopt::StructOpt,
     tungstenite::Message as WsMessage,
+    webrtc_common::{
+        request_keyframe, request_stats, set_encoder_bitrate, Command, ControlArgs, ControlRequest,
+        IceArgs, ImpairmentArgs, Metrics, MetricsArgs, RecoveryArgs, StatsArgs, StatsPoller,
+        StatsReporter,
+    },
 };
 
 // upgrade weak reference or return
@@ -94,6 +99,19 @@ pub struct Args {
     feed_id: u32,
     #[structopt(short, long, default_value = "vp8")]
     webrtc_video_codec: VideoParameter,
+    #[structopt(flatten)]
+    ice: IceArgs,
+    #[structopt(flatten)]
+    recovery: RecoveryArgs,
+    // Only the sent stream can be impaired, as nothing is received from Janus
+    #[structopt(flatten)]
+    impairment: ImpairmentArgs,
+    #[structopt(flatten)]
+    stats: StatsArgs,
+    #[structopt(flatten)]
+    metrics: MetricsArgs,
+    #[structopt(flatten)]
+    control: ControlArgs,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -194,6 +212,7 @@ struct PeerInner {
     handle: ConnectionHandle,
     bin: gst::Bin,
     webrtcbin: gst::Element,
+    recovery: RecoveryArgs,
     send_msg_tx: Arc<Mutex<mpsc::UnboundedSender<WsMessage>>>,
 }
 
@@ -209,12 +228,22 @@ impl Peer {
     fn on_negotiation_needed(&self) -> Result<(), anyhow::Error> {
         info!("starting negotiation with peer");
 
+        self.create_offer(false)
+    }
+
+    // Restart ICE after the connection failed. The new offer is sent with a configure request as
+    // we're already publishing
+    fn restart_ice(&self) -> Result<(), anyhow::Error> {
+        self.create_offer(true)
+    }
+
+    fn create_offer(&self, ice_restart: bool) -> Result<(), anyhow::Error> {
         let peer_clone = self.downgrade();
         let promise = gst::Promise::with_change_func(move |res| {
             let s = res.ok().flatten().expect("no answer");
             let peer = upgrade_weak!(peer_clone);
 
-            if let Err(err) = peer.on_offer_created(s) {
+            if let Err(err) = peer.on_offer_created(s, ice_restart) {
                 gst_element_error!(
                     peer.bin,
                     gst::LibraryError::Failed,
@@ -223,15 +252,23 @@ impl Peer {
             }
         });
 
-        self.webrtcbin
-            .emit("create-offer", &[&None::<gst::Structure>, &promise])?;
+        let options = if ice_restart {
+            Some(gst::Structure::new("options", &[("ice-restart", &true)]))
+        } else {
+            None
+        };
+        self.webrtcbin.emit("create-offer", &[&options, &promise])?;
 
         Ok(())
     }
 
     // Once webrtcbin has create the offer SDP for us, handle it by sending it to the peer via the
     // WebSocket connection
-    fn on_offer_created(&self, reply: &gst::StructureRef) -> Result<(), anyhow::Error> {
+    fn on_offer_created(
+        &self,
+        reply: &gst::StructureRef,
+        ice_restart: bool,
+    ) -> Result<(), anyhow::Error> {
         let offer = reply
             .get_value("offer")?
             .get::<gst_webrtc::WebRTCSessionDescription>()
@@ -251,7 +288,7 @@ impl Peer {
                  "session_id": self.handle.session_id,
                  "handle_id": self.handle.id,
                  "body": {
-                     "request": "publish",
+                     "request": if ice_restart { "configure" } else { "publish" },
                      "audio": true,
                      "video": true,
                  },
@@ -272,6 +309,38 @@ impl Peer {
         Ok(())
     }
 
+    // Handle a command from the control socket. Only video is published, from a fixed source
+    fn handle_control_request(&self, request: ControlRequest) {
+        let res = match request.command {
+            Command::Mute { ref media } | Command::Unmute { ref media } if media == "video" => self
+                .bin
+                .get_by_name("mute")
+                .expect("No mute valve")
+                .set_property("drop", &matches!(request.command, Command::Mute { .. }))
+                .map_err(anyhow::Error::from),
+            Command::SetBitrate { ref media, bitrate } if media == "video" => set_encoder_bitrate(
+                &self.bin.get_by_name("encoder").expect("No encoder"),
+                bitrate,
+            ),
+            Command::RequestKeyframe => {
+                request_keyframe(&self.bin.get_by_name("encoder").expect("No encoder"))
+            }
+            Command::DumpStats => {
+                request_stats(&self.webrtcbin, move |stats| {
+                    request.reply(
+                        serde_json::to_value(stats)
+                            .map(Some)
+                            .map_err(anyhow::Error::from),
+                    )
+                });
+                return;
+            }
+            _ => Err(anyhow!("Command not supported by the Janus demo")),
+        };
+
+        request.reply(res.map(|_| None));
+    }
+
     // Once webrtcbin has create the answer SDP for us, handle it by sending it to the peer via the
     // WebSocket connection
     fn on_answer_created(&self, reply: &gst::Structure) -> Result<(), anyhow::Error> {
@@ -298,6 +367,9 @@ impl Peer {
 
             let ret = gst_sdp::SDPMessage::parse_buffer(sdp.as_bytes())
                 .map_err(|_| anyhow!("Failed to parse SDP answer"))?;
+            if let Err(err) = self.recovery.verify_sdp(&ret) {
+                info!("Packet loss recovery is incomplete: {}", err);
+            }
             let answer =
                 gst_webrtc::WebRTCSessionDescription::new(gst_webrtc::WebRTCSDPType::Answer, ret);
 
@@ -410,11 +482,13 @@ pub struct JanusGateway {
     handle: ConnectionHandle,
     peer: Mutex<Peer>,
     send_ws_msg_rx: Option<mpsc::UnboundedReceiver<WsMessage>>,
+    control_rx: Option<BoxStream<'static, ControlRequest>>,
 }
 
 impl JanusGateway {
     pub async fn new(pipeline: gst::Bin) -> Result<Self, anyhow::Error> {
         let args = Args::from_args();
+        let control_rx = args.control.listen()?;
         let request = Request::builder()
             .uri(&args.server)
             .header("Sec-WebSocket-Protocol", "janus-protocol")
@@ -485,11 +559,13 @@ impl JanusGateway {
         let webrtcbin = pipeline
             .get_by_name("webrtcbin")
             .expect("can't find webrtcbin");
+        args.ice.configure_webrtcbin(&webrtcbin)?;
 
         let webrtc_codec = &args.webrtc_video_codec;
         let bin_description = &format!(
-            "{encoder} name=encoder ! {payloader} ! queue ! capsfilter name=webrtc-vsink caps=\"application/x-rtp,media=video,encoding-name={encoding_name},payload=96\"",
+            "valve name=mute ! {encoder} name=encoder ! {payloader}{impairment} ! queue ! capsfilter name=webrtc-vsink caps=\"application/x-rtp,media=video,encoding-name={encoding_name},payload=96\"",
             encoder=webrtc_codec.encoder, payloader=webrtc_codec.payloader,
+            impairment=args.impairment.send_stage(),
             encoding_name=webrtc_codec.encoding_name
         );
 
@@ -499,14 +575,14 @@ impl JanusGateway {
         pipeline.add(&encode_bin).expect("Failed to add encode bin");
 
         let video_queue = pipeline.get_by_name("vqueue").expect("No vqueue found");
-        let encoder = encode_bin.get_by_name("encoder").expect("No encoder");
+        let mute = encode_bin.get_by_name("mute").expect("No mute valve");
 
         let srcpad = video_queue
             .get_static_pad("src")
             .expect("Failed to get video queue src pad");
-        let sinkpad = encoder
+        let sinkpad = mute
             .get_static_pad("sink")
-            .expect("Failed to get sink pad from encoder");
+            .expect("Failed to get sink pad from mute valve");
 
         if let Ok(video_ghost_pad) = gst::GhostPad::with_target(Some("video_sink"), &sinkpad) {
             encode_bin.add_pad(&video_ghost_pad)?;
@@ -528,14 +604,17 @@ impl JanusGateway {
             webrtc_ghost_pad.link(&sinkpad2)?;
         }
 
-        if let Ok(transceiver) = webrtcbin.emit("get-transceiver", &[&0.to_value()]) {
-            if let Some(t) = transceiver {
-                if let Ok(obj) = t.get::<glib::Object>() {
-                    obj.expect("Invalid transceiver")
-                        .set_property("do-nack", &true.to_value())?;
-                }
-            }
-        }
+        let transceiver = webrtcbin
+            .emit("get-transceiver", &[&0.to_value()])?
+            .and_then(|transceiver| {
+                transceiver
+                    .get::<gst_webrtc::WebRTCRTPTransceiver>()
+                    .expect("Invalid transceiver")
+            })
+            .ok_or_else(|| anyhow!("No transceiver for the video stream"))?;
+        args.recovery.configure_transceiver(&transceiver)?;
+        // Janus relies on NACKs for the video, so they are always enabled
+        transceiver.set_property("do-nack", &true.to_value())?;
 
         let (send_ws_msg_tx, send_ws_msg_rx) = mpsc::unbounded::<WsMessage>();
 
@@ -548,6 +627,7 @@ impl JanusGateway {
             handle: connection_handle,
             bin: pipeline,
             webrtcbin,
+            recovery: args.recovery.clone(),
             send_msg_tx: Arc::new(Mutex::new(send_ws_msg_tx)),
         }));
 
@@ -567,6 +647,56 @@ impl JanusGateway {
                 None
             })?;
 
+        // Log the connection states and restart ICE if the connection to Janus fails
+        let peer_clone = peer.downgrade();
+        let restart = move || {
+            let peer = upgrade_weak!(peer_clone);
+
+            // Not from webrtcbin's own thread, as this creates a new offer
+            let peer_clone = peer.downgrade();
+            peer.bin.call_async(move |_pipeline| {
+                let peer = upgrade_weak!(peer_clone);
+
+                if let Err(err) = peer.restart_ice() {
+                    gst_element_error!(
+                        peer.bin,
+                        gst::LibraryError::Failed,
+                        ("Failed to restart ICE: {:?}", err)
+                    );
+                }
+            });
+        };
+        let stats_reporter = StatsReporter::new(&args.stats)?;
+        let peer_clone = peer.downgrade();
+        webrtc_common::watch_connection(
+            &peer.webrtcbin,
+            "janus",
+            None,
+            &args.ice,
+            stats_reporter.as_ref(),
+            Some(restart),
+            move |err| {
+                let peer = upgrade_weak!(peer_clone);
+                gst_element_error!(peer.bin, gst::LibraryError::Failed, ("{}", err));
+            },
+        );
+
+        // Periodically report the statistics of the stream if requested
+        let stats = StatsPoller::new(&peer.webrtcbin);
+        if let Some(stats_reporter) = stats_reporter {
+            stats_reporter.watch(&stats, None);
+        }
+
+        // Serve the metrics of the stream if requested. The bus is already watched by the
+        // application, so count its messages synchronously
+        if let Some(metrics) = Metrics::new(&args.metrics)? {
+            metrics.watch(&peer.webrtcbin, &stats, "janus");
+
+            let bus = peer.bin.get_bus().unwrap();
+            bus.enable_sync_message_emission();
+            bus.connect_sync_message(move |_bus, message| metrics.handle_bus_message(message));
+        }
+
         // Whenever there is a new ICE candidate, send it to the peer
         let peer_clone = peer.downgrade();
         peer.webrtcbin
@@ -601,6 +731,7 @@ impl JanusGateway {
             handle: connection_handle,
             peer: Mutex::new(peer),
             send_ws_msg_rx: Some(send_ws_msg_rx),
+            control_rx: Some(control_rx),
         })
     }
 
@@ -616,6 +747,12 @@ impl JanusGateway {
                 .expect("Invalid message receiver");
             let mut send_ws_msg_rx = send_ws_msg_rx.fuse();
 
+            let mut control_rx = self
+                .control_rx
+                .take()
+                .expect("Invalid control receiver")
+                .fuse();
+
             let timer = glib::interval_stream(10_000);
             let mut timer_fuse = timer.fuse();
 
@@ -656,6 +793,17 @@ impl JanusGateway {
                             }).to_string(),
                         ))
                     },
+                    // Handle commands from the control socket
+                    request = control_rx.select_next_some() => {
+                        if request.command == Command::Hangup {
+                            info!("hanging up");
+                            request.reply(Ok(None));
+                            break;
+                        }
+
+                        self.peer.lock().unwrap().handle_control_request(request);
+                        None
+                    },
                     // Once we're done, break the loop and return
                     complete => break,
                 };
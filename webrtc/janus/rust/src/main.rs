@@ -68,7 +68,7 @@ impl App {
 
     fn new() -> Result<Self, anyhow::Error> {
         let pipeline = gst::parse_launch(
-            &"webrtcbin name=webrtcbin stun-server=stun://stun.l.google.com:19302 \
+            &"webrtcbin name=webrtcbin \
              videotestsrc pattern=ball ! videoconvert ! queue name=vqueue"
                 .to_string(),
         )?;
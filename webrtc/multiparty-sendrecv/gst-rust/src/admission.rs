@@ -0,0 +1,313 @@
+// Admission control for the call
+//
+// Peers in the room are only added to the call if they're allowed to join and there is a free slot:
+// at most --max-peers peers at once, and new peers only while all peers together send less than
+// --max-inbound-bitrate. Denied peers are rejected right away, the others wait in a queue and are
+// admitted in order once a slot frees up. The bitrate of a newly admitted peer only counts once it
+// was measured, so the limit can be exceeded by a bit.
+
+use std::collections::VecDeque;
+
+use structopt::StructOpt;
+
+use webrtc_common::{Stats, StatsKind};
+
+#[derive(Debug, Clone, StructOpt)]
+pub struct AdmissionArgs {
+    // Maximum number of peers in the call at once
+    #[structopt(long)]
+    max_peers: Option<usize>,
+    // Only admit new peers while all peers together send less than this many bits per second
+    #[structopt(long)]
+    max_inbound_bitrate: Option<u64>,
+    // Only admit these peers. Can be given multiple times
+    #[structopt(long = "allow-peer", number_of_values = 1)]
+    allowed: Vec<u32>,
+    // Never admit these peers. Can be given multiple times
+    #[structopt(long = "deny-peer", number_of_values = 1)]
+    denied: Vec<u32>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Decision {
+    Admit,
+    // Why the peer has to wait, and its position in the queue starting at 1
+    Queue(String, usize),
+    Reject(String),
+}
+
+#[derive(Debug)]
+pub struct Admission {
+    args: AdmissionArgs,
+    // The waiting peers in order, and whether we send the offer to them once they're admitted
+    queue: VecDeque<(u32, bool)>,
+}
+
+impl Admission {
+    pub fn new(args: AdmissionArgs) -> Self {
+        Admission {
+            args,
+            queue: VecDeque::new(),
+        }
+    }
+
+    // Why no further peer can be admitted while peers peers are in the call and send
+    // inbound_bitrate bits per second together, if so
+    fn full(&self, peers: usize, inbound_bitrate: u64) -> Option<String> {
+        if let Some(max_peers) = self.args.max_peers {
+            if peers >= max_peers {
+                return Some(format!("the call is full with {} peers", peers));
+            }
+        }
+
+        if let Some(max_inbound_bitrate) = self.args.max_inbound_bitrate {
+            if inbound_bitrate >= max_inbound_bitrate {
+                return Some(format!(
+                    "the call already receives {} of at most {} bits per second",
+                    inbound_bitrate, max_inbound_bitrate
+                ));
+            }
+        }
+
+        None
+    }
+
+    // Decide about a peer that wants to join the call. Peers that can't join yet are queued, also
+    // if others are waiting already
+    pub fn check(
+        &mut self,
+        peer_id: u32,
+        offer: bool,
+        peers: usize,
+        inbound_bitrate: u64,
+    ) -> Decision {
+        if self.args.denied.contains(&peer_id)
+            || (!self.args.allowed.is_empty() && !self.args.allowed.contains(&peer_id))
+        {
+            return Decision::Reject(format!("peer {} is not allowed to join the call", peer_id));
+        }
+
+        let reason = match self.full(peers, inbound_bitrate) {
+            Some(reason) => reason,
+            None if self.queue.is_empty() => return Decision::Admit,
+            None => String::from("other peers are waiting to join the call"),
+        };
+
+        let position = match self.queue.iter().position(|(id, _)| *id == peer_id) {
+            Some(idx) => idx + 1,
+            None => {
+                self.queue.push_back((peer_id, offer));
+                self.queue.len()
+            }
+        };
+
+        Decision::Queue(reason, position)
+    }
+
+    // Take the next waiting peer out of the queue if there's a free slot for it now
+    pub fn next(&mut self, peers: usize, inbound_bitrate: u64) -> Option<(u32, bool)> {
+        if self.full(peers, inbound_bitrate).is_some() {
+            return None;
+        }
+
+        self.queue.pop_front()
+    }
+
+    // Remove a peer that left the room from the queue, returns whether it was waiting
+    pub fn remove(&mut self, peer_id: u32) -> bool {
+        let len = self.queue.len();
+        self.queue.retain(|(id, _)| *id != peer_id);
+
+        self.queue.len() != len
+    }
+
+    // Only keep the waiting peers for which the function returns true, e.g. the ones still in the
+    // room
+    pub fn retain<F: FnMut(u32) -> bool>(&mut self, mut func: F) {
+        self.queue.retain(|(id, _)| func(*id));
+    }
+
+    // Whether the inbound bitrate has to be measured
+    pub fn limits_bitrate(&self) -> bool {
+        self.args.max_inbound_bitrate.is_some()
+    }
+}
+
+// Inbound bitrate of a peer, from the bytes received on all its streams between two statistics
+#[derive(Debug, Default)]
+pub struct BitrateMeter {
+    // Bytes and timestamp in milliseconds of the last statistics
+    last: Option<(u64, f64)>,
+    bitrate: u64,
+}
+
+impl BitrateMeter {
+    // In bits per second
+    pub fn bitrate(&self) -> u64 {
+        self.bitrate
+    }
+
+    pub fn update(&mut self, stats: &[Stats]) {
+        let mut bytes = 0;
+        let mut timestamp = None;
+        for stats in stats {
+            if let StatsKind::InboundRtp(ref inbound) = stats.kind {
+                bytes += inbound.bytes_received.unwrap_or(0);
+                timestamp = Some(stats.timestamp);
+            }
+        }
+        let timestamp = match timestamp {
+            Some(timestamp) => timestamp,
+            None => return,
+        };
+
+        if let Some((last_bytes, last_timestamp)) = self.last {
+            if timestamp > last_timestamp {
+                self.bitrate = (bytes.saturating_sub(last_bytes) as f64 * 8.0 * 1000.0
+                    / (timestamp - last_timestamp)) as u64;
+            }
+        }
+        self.last = Some((bytes, timestamp));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn admission(max_peers: Option<usize>, max_inbound_bitrate: Option<u64>) -> Admission {
+        Admission::new(AdmissionArgs {
+            max_peers,
+            max_inbound_bitrate,
+            allowed: Vec::new(),
+            denied: Vec::new(),
+        })
+    }
+
+    #[test]
+    fn unlimited() {
+        let mut admission = admission(None, None);
+
+        assert_eq!(
+            admission.check(1, false, 100, 1_000_000_000),
+            Decision::Admit
+        );
+        assert_eq!(admission.next(100, 1_000_000_000), None);
+        assert!(!admission.limits_bitrate());
+    }
+
+    #[test]
+    fn max_peers() {
+        let mut admission = admission(Some(2), None);
+
+        assert_eq!(admission.check(1, false, 1, 0), Decision::Admit);
+        assert_eq!(
+            admission.check(3, true, 2, 0),
+            Decision::Queue("the call is full with 2 peers".into(), 1)
+        );
+        assert_eq!(
+            admission.check(4, false, 2, 0),
+            Decision::Queue("the call is full with 2 peers".into(), 2)
+        );
+
+        // Asking again keeps the position
+        assert_eq!(
+            admission.check(3, true, 2, 0),
+            Decision::Queue("the call is full with 2 peers".into(), 1)
+        );
+
+        // Admitted in order as slots free up, with whether we send the offer
+        assert_eq!(admission.next(2, 0), None);
+        assert_eq!(admission.next(1, 0), Some((3, true)));
+        assert_eq!(admission.next(1, 0), Some((4, false)));
+        assert_eq!(admission.next(1, 0), None);
+    }
+
+    #[test]
+    fn max_inbound_bitrate() {
+        let mut admission = admission(None, Some(1_000_000));
+        assert!(admission.limits_bitrate());
+
+        assert_eq!(admission.check(1, false, 3, 999_999), Decision::Admit);
+        assert_eq!(
+            admission.check(2, false, 3, 1_000_000),
+            Decision::Queue(
+                "the call already receives 1000000 of at most 1000000 bits per second".into(),
+                1
+            )
+        );
+
+        assert_eq!(admission.next(3, 1_500_000), None);
+        assert_eq!(admission.next(3, 500_000), Some((2, false)));
+    }
+
+    #[test]
+    fn waiting_peers_first() {
+        let mut admission = admission(Some(2), None);
+        assert!(matches!(
+            admission.check(1, false, 2, 0),
+            Decision::Queue(_, 1)
+        ));
+
+        // A free slot goes to the peer that waits already
+        assert_eq!(
+            admission.check(2, false, 1, 0),
+            Decision::Queue("other peers are waiting to join the call".into(), 2)
+        );
+        assert_eq!(admission.next(1, 0), Some((1, false)));
+        assert_eq!(admission.next(1, 0), Some((2, false)));
+        assert_eq!(admission.check(3, false, 1, 0), Decision::Admit);
+    }
+
+    #[test]
+    fn allow_and_deny() {
+        let mut admission = Admission::new(AdmissionArgs {
+            max_peers: Some(1),
+            max_inbound_bitrate: None,
+            allowed: vec![1, 2, 3],
+            denied: vec![2],
+        });
+
+        assert_eq!(admission.check(1, false, 0, 0), Decision::Admit);
+        assert_eq!(
+            admission.check(2, false, 0, 0),
+            Decision::Reject("peer 2 is not allowed to join the call".into())
+        );
+        assert_eq!(
+            admission.check(4, false, 0, 0),
+            Decision::Reject("peer 4 is not allowed to join the call".into())
+        );
+
+        // Rejected peers are not queued, even if the call is full
+        assert_eq!(
+            admission.check(4, false, 1, 0),
+            Decision::Reject("peer 4 is not allowed to join the call".into())
+        );
+        assert!(matches!(
+            admission.check(3, false, 1, 0),
+            Decision::Queue(_, 1)
+        ));
+        assert_eq!(admission.next(0, 0), Some((3, false)));
+        assert_eq!(admission.next(0, 0), None);
+    }
+
+    #[test]
+    fn remove_waiting_peers() {
+        let mut admission = admission(Some(1), None);
+        for peer_id in 1..=4 {
+            admission.check(peer_id, false, 1, 0);
+        }
+
+        assert!(admission.remove(2));
+        assert!(!admission.remove(2));
+        admission.retain(|peer_id| peer_id != 3);
+
+        // The positions move up
+        assert_eq!(
+            admission.check(4, false, 1, 0),
+            Decision::Queue("the call is full with 1 peers".into(), 2)
+        );
+        assert_eq!(admission.next(0, 0), Some((1, false)));
+        assert_eq!(admission.next(0, 0), Some((4, false)));
+    }
+}
@@ -0,0 +1,230 @@
+// Forwarding of the peers' RTP streams to the other peers in SFU mode
+//
+// Every stream a peer sends us goes into a tee in its bin without being depayloaded or decoded.
+// For every other peer the tee is linked through ghost pads to a new branch in that peer's bin,
+// which ends in a new sink pad of its webrtcbin. This adds a transceiver, so the stream is sent
+// after the next negotiation. Keyframe requests of the receivers travel upstream through the tee
+// and are sent to the sender as PLI by its webrtcbin.
+//
+// webrtcbin can't remove transceivers, and it doesn't reuse inactive ones for new sink pads. The
+// transceivers of streams that stopped stay in the SDP as inactive media, so the SDP of a
+// connection grows with every stream ever forwarded on it. This is fine for the short calls of the
+// demo, but long-running rooms with many peers coming and going would need to reconnect peers.
+
+use gst::prelude::*;
+
+use webrtc_common::request_keyframe_upstream;
+
+use anyhow::anyhow;
+
+// A stream received from one peer, to be forwarded to the others
+#[derive(Debug)]
+pub struct Stream {
+    // Name of the webrtcbin pad it arrived on, unique within the peer's bin
+    name: String,
+    tee: gst::Element,
+    caps: gst::Caps,
+}
+
+impl Stream {
+    // Add a tee for the stream arriving on this pad of the peer's webrtcbin. The pad still has to
+    // be linked to the returned sink pad
+    pub fn new(bin: &gst::Bin, pad: &gst::Pad) -> Result<(Stream, gst::Pad), anyhow::Error> {
+        let name = pad.get_name().to_string();
+        let caps = pad
+            .get_current_caps()
+            .ok_or_else(|| anyhow!("No caps on {}", name))?;
+
+        let tee = gst::ElementFactory::make("tee", Some(&format!("forward-{}", name)))?;
+        tee.set_property("allow-not-linked", &true)?;
+        bin.add(&tee)?;
+        tee.sync_state_with_parent()?;
+        let sinkpad = tee.get_static_pad("sink").unwrap();
+
+        Ok((Stream { name, tee, caps }, sinkpad))
+    }
+
+    pub fn is_video(&self) -> bool {
+        self.caps
+            .get_structure(0)
+            .and_then(|s| s.get::<&str>("media").ok().flatten())
+            == Some("video")
+    }
+
+    // Ask the sender for a keyframe, e.g. for a new receiver
+    pub fn request_keyframe(&self) -> Result<(), anyhow::Error> {
+        request_keyframe_upstream(&self.tee.get_static_pad("sink").unwrap())
+    }
+
+    // Forward the stream from the bin of peer from_id to the bin of peer to_id, with the optional
+    // impairment stage in between. Returns the transceiver that was added to the receiver's
+    // webrtcbin, or nothing if the stream is forwarded there already
+    pub fn forward(
+        &self,
+        from_id: u32,
+        from: &gst::Bin,
+        to_id: u32,
+        to: &gst::Bin,
+        impairment: &str,
+    ) -> Result<Option<gst_webrtc::WebRTCRTPTransceiver>, anyhow::Error> {
+        let branch_name = format!("forward-{}-{}", from_id, self.name);
+        if to.get_by_name(&branch_name).is_some() {
+            return Ok(None);
+        }
+
+        // The caps tell the receiver's webrtcbin what to offer for the stream
+        let branch = gst::parse_bin_from_description(
+            &format!("queue{} ! capsfilter name=caps", impairment),
+            true,
+        )?;
+        branch.set_name(&branch_name)?;
+        branch
+            .get_by_name("caps")
+            .unwrap()
+            .set_property("caps", &self.caps)?;
+        to.add(&branch)?;
+
+        let webrtcbin = to.get_by_name("webrtcbin").unwrap();
+        let webrtc_sinkpad = webrtcbin
+            .get_request_pad("sink_%u")
+            .ok_or_else(|| anyhow!("Can't add a stream to peer {}", to_id))?;
+        branch
+            .get_static_pad("src")
+            .unwrap()
+            .link(&webrtc_sinkpad)?;
+        branch.sync_state_with_parent()?;
+
+        // Ghost pads on both peer bins connect the tee with the branch
+        let srcpad = gst::GhostPad::with_target(
+            Some(&format!("forward_{}_{}", self.name, to_id)),
+            &self.tee.get_request_pad("src_%u").unwrap(),
+        )?;
+        let sinkpad = gst::GhostPad::with_target(
+            Some(&branch_name),
+            &branch.get_static_pad("sink").unwrap(),
+        )?;
+        srcpad.set_active(true)?;
+        from.add_pad(&srcpad)?;
+        sinkpad.set_active(true)?;
+        to.add_pad(&sinkpad)?;
+
+        // Once the receiver is removed, release the pad on the tee again. If the sender is removed
+        // instead, stop() cleans up at the receiver
+        srcpad.connect_unlinked(|pad, _peer| super::release_ghost_pad(pad));
+        srcpad.link(&sinkpad)?;
+
+        let transceiver = webrtc_sinkpad
+            .get_property("transceiver")?
+            .get::<gst_webrtc::WebRTCRTPTransceiver>()?
+            .ok_or_else(|| anyhow!("No transceiver for the stream to peer {}", to_id))?;
+
+        Ok(Some(transceiver))
+    }
+}
+
+// Stop forwarding all streams of peer from_id to the bin of another peer. The transceivers of the
+// streams become inactive but stay, see above, which needs a new negotiation. Returns whether
+// anything was forwarded
+pub fn stop(from_id: u32, to: &gst::Bin) -> bool {
+    let prefix = format!("forward-{}-", from_id);
+    let branches = to
+        .get_children()
+        .into_iter()
+        .filter(|element| element.get_name().starts_with(&prefix))
+        .collect::<Vec<_>>();
+
+    for branch in &branches {
+        let branch_srcpad = branch.get_static_pad("src").unwrap();
+        if let Some(webrtc_sinkpad) = branch_srcpad.get_peer() {
+            let transceiver = webrtc_sinkpad
+                .get_property("transceiver")
+                .ok()
+                .and_then(|value| value.get::<gst_webrtc::WebRTCRTPTransceiver>().ok())
+                .flatten();
+            if let Some(transceiver) = transceiver {
+                let _ = transceiver.set_property(
+                    "direction",
+                    &gst_webrtc::WebRTCRTPTransceiverDirection::Inactive,
+                );
+            }
+            let _ = branch_srcpad.unlink(&webrtc_sinkpad);
+            if let Some(webrtcbin) = webrtc_sinkpad.get_parent_element() {
+                webrtcbin.release_request_pad(&webrtc_sinkpad);
+            }
+        }
+
+        // Removing the ghost pad unlinks it from the sender, which releases the pad on its tee
+        if let Some(sinkpad) = to.get_static_pad(&branch.get_name()) {
+            let _ = to.remove_pad(&sinkpad);
+        }
+
+        let _ = to.remove(branch);
+        let _ = branch.set_state(gst::State::Null);
+    }
+
+    !branches.is_empty()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stream(media: &str) -> Stream {
+        gst::init().unwrap();
+
+        Stream {
+            name: String::from("src_0"),
+            tee: gst::ElementFactory::make("tee", None).unwrap(),
+            caps: gst::Caps::builder("application/x-rtp")
+                .field("media", media)
+                .build(),
+        }
+    }
+
+    // A bin with a forwarded branch, without the webrtcbin it's normally linked to
+    fn add_branch(bin: &gst::Bin, name: &str) {
+        let branch = gst::ElementFactory::make("queue", Some(name)).unwrap();
+        bin.add(&branch).unwrap();
+        let sinkpad =
+            gst::GhostPad::with_target(Some(name), &branch.get_static_pad("sink").unwrap())
+                .unwrap();
+        bin.add_pad(&sinkpad).unwrap();
+    }
+
+    #[test]
+    fn media_kind() {
+        assert!(stream("video").is_video());
+        assert!(!stream("audio").is_video());
+    }
+
+    #[test]
+    fn forward_only_once() {
+        let stream = stream("video");
+        let from = gst::Bin::new(Some("bin-1"));
+        let to = gst::Bin::new(Some("bin-2"));
+        add_branch(&to, "forward-1-src_0");
+
+        assert!(stream.forward(1, &from, 2, &to, "").unwrap().is_none());
+    }
+
+    #[test]
+    fn stop_forwarding() {
+        gst::init().unwrap();
+
+        let to = gst::Bin::new(Some("bin-3"));
+        add_branch(&to, "forward-1-src_0");
+        add_branch(&to, "forward-1-src_1");
+        add_branch(&to, "forward-2-src_0");
+
+        assert!(stop(1, &to));
+        assert!(to.get_by_name("forward-1-src_0").is_none());
+        assert!(to.get_by_name("forward-1-src_1").is_none());
+        assert!(to.get_static_pad("forward-1-src_0").is_none());
+        assert!(to.get_by_name("forward-2-src_0").is_some());
+        assert!(to.get_static_pad("forward-2-src_0").is_some());
+
+        // Nothing left to stop
+        assert!(!stop(1, &to));
+        assert!(!stop(3, &to));
+    }
+}
@@ -0,0 +1,297 @@
+// Ladder of shared video encodings at different bitrates
+//
+// Instead of one video encoding for all peers, the video is encoded at 2 or 3 bitrates, each into
+// its own tee. Every peer is linked to one of these rungs and switched between them depending on
+// its connection: the available outgoing bitrate of its candidate pair, if webrtcbin estimates it,
+// caps the rung, high packet loss reported by the peer moves it down one rung right away, and
+// after a while without loss it moves up one rung again. New peers start on the lowest rung.
+
+use gst::prelude::*;
+
+use webrtc_common::{Stats, StatsKind};
+
+use anyhow::{anyhow, bail};
+
+// Fraction of lost packets above which a peer moves down one rung
+const HIGH_LOSS: f64 = 0.1;
+// Fraction of lost packets below which a peer's connection counts as good
+const LOW_LOSS: f64 = 0.02;
+// Number of statistics in a row with a good connection before a peer moves up one rung
+const GOOD_REPORTS: u32 = 3;
+
+// Bitrates of the rungs in bits per second, from the lowest to the highest
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ladder(Vec<u32>);
+
+impl std::str::FromStr for Ladder {
+    type Err = anyhow::Error;
+
+    // Comma-separated bitrates, e.g. 300000,1000000,2500000
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut bitrates = s
+            .split(',')
+            .map(|bitrate| {
+                bitrate
+                    .trim()
+                    .parse::<u32>()
+                    .map_err(|_| anyhow!("Invalid bitrate {} in ladder {}", bitrate, s))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        bitrates.sort_unstable();
+        bitrates.dedup();
+        if bitrates.len() < 2 || bitrates.len() > 3 {
+            bail!("Invalid ladder {}. Give 2 or 3 different bitrates", s);
+        }
+
+        Ok(Ladder(bitrates))
+    }
+}
+
+impl Ladder {
+    // The encoders of all rungs in gst-launch syntax, fed from the tee with the given name. Each
+    // rung starts with a queue named video-rung-N and ends in a tee named video-tee-N
+    pub fn description(&self, tee: &str, encoder: &str) -> String {
+        (0..self.0.len())
+            .map(|idx| {
+                format!(
+                    "{tee}. ! queue name=video-rung-{idx} ! {encoder} ! tee name=video-tee-{idx} ! \
+                     queue ! fakesink sync=true",
+                    tee = tee,
+                    idx = idx,
+                    encoder = encoder,
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+// One encoding of the ladder in the pipeline
+#[derive(Debug)]
+pub struct Rung {
+    pub bitrate: u32,
+    pub encoder: gst::Element,
+    pub tee: gst::Element,
+}
+
+impl Rung {
+    // Find the elements of all rungs created from Ladder::description()
+    pub fn find_all(ladder: &Ladder, pipeline: &gst::Pipeline) -> Result<Vec<Rung>, anyhow::Error> {
+        ladder
+            .0
+            .iter()
+            .enumerate()
+            .map(|(idx, bitrate)| {
+                // The encoder is the first element after the queue
+                let queue = pipeline
+                    .get_by_name(&format!("video-rung-{}", idx))
+                    .ok_or_else(|| anyhow!("No queue for the {} bit/s video", bitrate))?;
+                let encoder = queue
+                    .get_static_pad("src")
+                    .and_then(|pad| pad.get_peer())
+                    .and_then(|pad| pad.get_parent_element())
+                    .ok_or_else(|| anyhow!("No encoder for the {} bit/s video", bitrate))?;
+                let tee = pipeline
+                    .get_by_name(&format!("video-tee-{}", idx))
+                    .ok_or_else(|| anyhow!("No tee for the {} bit/s video", bitrate))?;
+
+                Ok(Rung {
+                    bitrate: *bitrate,
+                    encoder,
+                    tee,
+                })
+            })
+            .collect()
+    }
+}
+
+// Selects the rung for one peer from its statistics
+#[derive(Debug, Default)]
+pub struct RungSelector {
+    rung: usize,
+    good_reports: u32,
+}
+
+impl RungSelector {
+    // Update with the latest statistics of the peer, returns the new rung if it changed
+    pub fn update(&mut self, bitrates: &[u32], stats: &[Stats]) -> Option<usize> {
+        let mut loss = None;
+        let mut available_bitrate = None;
+        for stats in stats {
+            match stats.kind {
+                StatsKind::RemoteInboundRtp(ref remote) => {
+                    if let Some(fraction_lost) = remote.fraction_lost {
+                        loss =
+                            Some(loss.map_or(fraction_lost, |loss: f64| loss.max(fraction_lost)));
+                    }
+                }
+                StatsKind::CandidatePair(ref pair) => {
+                    if let Some(bitrate) = pair.available_outgoing_bitrate {
+                        available_bitrate = Some(
+                            available_bitrate
+                                .map_or(bitrate, |available: f64| available.max(bitrate)),
+                        );
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        // The highest rung that fits into the estimated bandwidth, but at least the lowest one
+        let max_rung = match available_bitrate {
+            Some(available) => bitrates
+                .iter()
+                .rposition(|bitrate| f64::from(*bitrate) <= available)
+                .unwrap_or(0),
+            None => bitrates.len() - 1,
+        };
+
+        let mut rung = self.rung.min(max_rung);
+        match loss {
+            Some(loss) if loss > HIGH_LOSS => {
+                rung = rung.saturating_sub(1);
+                self.good_reports = 0;
+            }
+            Some(loss) if loss < LOW_LOSS => {
+                self.good_reports += 1;
+                if self.good_reports >= GOOD_REPORTS && rung < max_rung {
+                    rung += 1;
+                    self.good_reports = 0;
+                }
+            }
+            _ => self.good_reports = 0,
+        }
+
+        if rung == self.rung {
+            return None;
+        }
+        self.rung = rung;
+
+        Some(rung)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use webrtc_common::{CandidatePair, RemoteInboundRtp};
+
+    const BITRATES: [u32; 3] = [300_000, 1_000_000, 2_500_000];
+
+    fn loss(fraction_lost: f64) -> Stats {
+        Stats {
+            id: "rtp-remote-inbound-stream-stats_1234".into(),
+            timestamp: 1000.0,
+            kind: StatsKind::RemoteInboundRtp(RemoteInboundRtp {
+                ssrc: Some(1234),
+                packets_lost: None,
+                fraction_lost: Some(fraction_lost),
+                jitter: None,
+                round_trip_time: None,
+            }),
+        }
+    }
+
+    fn available(bitrate: f64) -> Stats {
+        Stats {
+            id: "ice-candidate-pair_0".into(),
+            timestamp: 1000.0,
+            kind: StatsKind::CandidatePair(CandidatePair {
+                local_candidate_id: None,
+                remote_candidate_id: None,
+                nominated: None,
+                bytes_sent: None,
+                bytes_received: None,
+                current_round_trip_time: None,
+                available_outgoing_bitrate: Some(bitrate),
+            }),
+        }
+    }
+
+    #[test]
+    fn parse_ladder() {
+        assert_eq!(
+            "2500000, 300000,1000000".parse::<Ladder>().unwrap(),
+            Ladder(BITRATES.to_vec())
+        );
+        assert!("300000".parse::<Ladder>().is_err());
+        assert!("300000,300000".parse::<Ladder>().is_err());
+        assert!("1,2,3,4".parse::<Ladder>().is_err());
+        assert!("300000,fast".parse::<Ladder>().is_err());
+    }
+
+    #[test]
+    fn missing_rungs() {
+        gst::init().unwrap();
+
+        let ladder = Ladder(BITRATES.to_vec());
+        assert!(Rung::find_all(&ladder, &gst::Pipeline::new(None)).is_err());
+    }
+
+    #[test]
+    fn switch_up_and_down() {
+        let mut selector = RungSelector::default();
+
+        // Up one rung after every few reports without loss, up to the highest
+        for rung in 1..=2 {
+            assert_eq!(selector.update(&BITRATES, &[loss(0.0)]), None);
+            assert_eq!(selector.update(&BITRATES, &[loss(0.01)]), None);
+            assert_eq!(selector.update(&BITRATES, &[loss(0.0)]), Some(rung));
+        }
+        for _ in 0..5 {
+            assert_eq!(selector.update(&BITRATES, &[loss(0.0)]), None);
+        }
+
+        // Down one rung right away on high loss of any stream, down to the lowest
+        assert_eq!(selector.update(&BITRATES, &[loss(0.0), loss(0.2)]), Some(1));
+        assert_eq!(selector.update(&BITRATES, &[loss(0.2)]), Some(0));
+        assert_eq!(selector.update(&BITRATES, &[loss(0.2)]), None);
+    }
+
+    #[test]
+    fn moderate_loss() {
+        let mut selector = RungSelector::default();
+
+        // Neither good nor bad, or unknown, starts counting the good reports again
+        assert_eq!(selector.update(&BITRATES, &[loss(0.0)]), None);
+        assert_eq!(selector.update(&BITRATES, &[loss(0.0)]), None);
+        assert_eq!(selector.update(&BITRATES, &[loss(0.05)]), None);
+        assert_eq!(selector.update(&BITRATES, &[loss(0.0)]), None);
+        assert_eq!(selector.update(&BITRATES, &[loss(0.0)]), None);
+        assert_eq!(selector.update(&BITRATES, &[]), None);
+        assert_eq!(selector.update(&BITRATES, &[loss(0.0)]), None);
+        assert_eq!(selector.update(&BITRATES, &[loss(0.0)]), None);
+        assert_eq!(selector.update(&BITRATES, &[loss(0.0)]), Some(1));
+    }
+
+    #[test]
+    fn available_bitrate() {
+        let mut selector = RungSelector::default();
+        for _ in 0..6 {
+            selector.update(&BITRATES, &[loss(0.0)]);
+        }
+
+        // Capped by the estimated bandwidth, even without loss
+        assert_eq!(
+            selector.update(&BITRATES, &[loss(0.0), available(1_200_000.0)]),
+            Some(1)
+        );
+        for _ in 0..5 {
+            assert_eq!(
+                selector.update(&BITRATES, &[loss(0.0), available(1_200_000.0)]),
+                None
+            );
+        }
+        assert_eq!(
+            selector.update(&BITRATES, &[loss(0.0), available(100_000.0)]),
+            Some(0)
+        );
+
+        // And up again right away once there's room, as the connection was good all along
+        assert_eq!(
+            selector.update(&BITRATES, &[loss(0.0), available(3_000_000.0)]),
+            Some(1)
+        );
+    }
+}
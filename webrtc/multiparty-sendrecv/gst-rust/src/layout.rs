@@ -0,0 +1,498 @@
+// Layouts of the peers' videos in the compositor
+//
+// A layout assigns a rectangle of the output to each peer. The videos are scaled into their
+// rectangle with their aspect ratio kept, leaving black bars around them.
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use serde_derive::Deserialize;
+
+use gst::prelude::*;
+
+use anyhow::Context;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rect {
+    pub x: i32,
+    pub y: i32,
+    pub width: i32,
+    pub height: i32,
+}
+
+impl Rect {
+    // The largest rectangle with the aspect ratio of the video that fits into this one, centered
+    fn fit(self, video_width: i32, video_height: i32) -> Rect {
+        if video_width <= 0 || video_height <= 0 {
+            return self;
+        }
+
+        // Compare width / height of both without rounding
+        let (width, height) =
+            if self.width as i64 * video_height as i64 > video_width as i64 * self.height as i64 {
+                (
+                    (self.height as i64 * video_width as i64 / video_height as i64) as i32,
+                    self.height,
+                )
+            } else {
+                (
+                    self.width,
+                    (self.width as i64 * video_height as i64 / video_width as i64) as i32,
+                )
+            };
+
+        Rect {
+            x: self.x + (self.width - width) / 2,
+            y: self.y + (self.height - height) / 2,
+            width,
+            height,
+        }
+    }
+}
+
+// A rectangle of a custom layout, relative to the output size. Regions pinned to a peer are only
+// used for that peer, the others are filled with the remaining peers in order
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct Region {
+    pub x: f64,
+    pub y: f64,
+    pub width: f64,
+    pub height: f64,
+    #[serde(default)]
+    pub peer_id: Option<u32>,
+}
+
+// A custom layout file, e.g. {"regions": [{"x": 0, "y": 0, "width": 0.5, "height": 1}, ...]}.
+// Later regions are drawn on top of earlier ones
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+struct LayoutFile {
+    regions: Vec<Region>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Layout {
+    // All peers in a grid of equally sized tiles
+    Grid,
+    // The focused peer large, with the others in a filmstrip below
+    Speaker,
+    // The focused peer on the whole output, with the others as small pictures in front of it
+    PictureInPicture,
+    // The regions of a layout file
+    Custom(PathBuf, Vec<Region>),
+}
+
+impl std::str::FromStr for Layout {
+    type Err = anyhow::Error;
+
+    // grid, speaker, pip or the path to a JSON layout file
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "grid" => Ok(Layout::Grid),
+            "speaker" => Ok(Layout::Speaker),
+            "pip" => Ok(Layout::PictureInPicture),
+            path => {
+                let file = std::fs::File::open(path)
+                    .with_context(|| format!("Invalid layout {}, no such file", path))?;
+                let layout: LayoutFile = serde_json::from_reader(file)
+                    .with_context(|| format!("Invalid layout file {}", path))?;
+                Ok(Layout::Custom(PathBuf::from(path), layout.regions))
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for Layout {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Layout::Grid => f.write_str("grid"),
+            Layout::Speaker => f.write_str("speaker"),
+            Layout::PictureInPicture => f.write_str("pip"),
+            Layout::Custom(path, _) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+// Rectangles for count tiles in a grid, with the last row centered if it isn't full
+fn grid(count: usize, area: Rect) -> Vec<Rect> {
+    if count == 0 {
+        return Vec::new();
+    }
+
+    let columns = (1..).find(|columns| columns * columns >= count).unwrap();
+    let rows = (count + columns - 1) / columns;
+    let width = area.width / columns as i32;
+    let height = area.height / rows as i32;
+
+    (0..count)
+        .map(|idx| {
+            let row = idx / columns;
+            let in_row = (count - row * columns).min(columns);
+            let offset = (columns - in_row) as i32 * width / 2;
+
+            Rect {
+                x: area.x + offset + (idx % columns) as i32 * width,
+                y: area.y + row as i32 * height,
+                width,
+                height,
+            }
+        })
+        .collect()
+}
+
+impl Layout {
+    // The rectangle of each peer, in the order they're stacked from bottom to top. Peers without a
+    // rectangle are not shown
+    pub fn tiles(
+        &self,
+        peers: &[u32],
+        focus: Option<u32>,
+        width: i32,
+        height: i32,
+    ) -> Vec<(u32, Rect)> {
+        let output = Rect {
+            x: 0,
+            y: 0,
+            width,
+            height,
+        };
+
+        // Without a known focused peer the first one is used
+        let focus = focus
+            .filter(|focus| peers.contains(focus))
+            .or_else(|| peers.first().copied());
+        let others = peers
+            .iter()
+            .copied()
+            .filter(|peer_id| Some(*peer_id) != focus)
+            .collect::<Vec<_>>();
+
+        match self {
+            Layout::Grid => peers
+                .iter()
+                .copied()
+                .zip(grid(peers.len(), output))
+                .collect(),
+            Layout::Speaker if others.is_empty() => {
+                focus.into_iter().map(|f| (f, output)).collect()
+            }
+            Layout::Speaker => {
+                let filmstrip = Rect {
+                    x: 0,
+                    y: height * 3 / 4,
+                    width,
+                    height: height / 4,
+                };
+                let width = width / others.len() as i32;
+                let mut tiles = vec![(
+                    focus.unwrap(),
+                    Rect {
+                        height: height * 3 / 4,
+                        ..output
+                    },
+                )];
+                tiles.extend(others.iter().enumerate().map(|(idx, peer_id)| {
+                    (
+                        *peer_id,
+                        Rect {
+                            x: idx as i32 * width,
+                            width,
+                            ..filmstrip
+                        },
+                    )
+                }));
+                tiles
+            }
+            Layout::PictureInPicture => {
+                // Small pictures of a quarter of the output size from the bottom right to the left,
+                // continuing in the row above once a row is full. If there are too many of them to
+                // fit, they get smaller so that there are as many rows as columns
+                let columns = (4..)
+                    .find(|columns| columns * columns >= others.len())
+                    .unwrap() as i32;
+                let (small_width, small_height) = (width / columns, height / columns);
+                let mut tiles = focus.into_iter().map(|f| (f, output)).collect::<Vec<_>>();
+                tiles.extend(others.iter().enumerate().map(|(idx, peer_id)| {
+                    let (row, column) = (idx as i32 / columns, idx as i32 % columns);
+                    (
+                        *peer_id,
+                        Rect {
+                            x: width - (column + 1) * small_width,
+                            y: height - (row + 1) * small_height,
+                            width: small_width,
+                            height: small_height,
+                        },
+                    )
+                }));
+                tiles
+            }
+            Layout::Custom(_, regions) => {
+                let scale = |region: &Region| Rect {
+                    x: (region.x * width as f64) as i32,
+                    y: (region.y * height as f64) as i32,
+                    width: (region.width * width as f64) as i32,
+                    height: (region.height * height as f64) as i32,
+                };
+
+                let mut unpinned = peers.iter().copied().filter(|peer_id| {
+                    !regions
+                        .iter()
+                        .any(|region| region.peer_id == Some(*peer_id))
+                });
+                regions
+                    .iter()
+                    .filter_map(|region| match region.peer_id {
+                        Some(peer_id) if peers.contains(&peer_id) => Some((peer_id, scale(region))),
+                        Some(_) => None,
+                        None => unpinned.next().map(|peer_id| (peer_id, scale(region))),
+                    })
+                    .collect()
+            }
+        }
+    }
+}
+
+// The peers' sink pads on the compositor and how they are laid out
+#[derive(Debug)]
+pub struct Composition {
+    pub layout: Layout,
+    // The peer shown largest by the speaker and picture-in-picture layouts, otherwise the active
+    // speaker is
+    pub focus: Option<u32>,
+    pub speaker: Option<u32>,
+    pub pads: BTreeMap<u32, gst::Pad>,
+}
+
+impl Composition {
+    pub fn new(layout: Layout) -> Composition {
+        Composition {
+            layout,
+            focus: None,
+            speaker: None,
+            pads: BTreeMap::new(),
+        }
+    }
+
+    // Recompute the geometry of all compositor pads for an output of the given size
+    pub fn apply(&self, width: i32, height: i32) {
+        let peers = self.pads.keys().copied().collect::<Vec<_>>();
+        let tiles = self
+            .layout
+            .tiles(&peers, self.focus.or(self.speaker), width, height);
+
+        for (peer_id, pad) in &self.pads {
+            if !tiles
+                .iter()
+                .any(|(tile_peer_id, _)| tile_peer_id == peer_id)
+            {
+                pad.set_property("alpha", &0.0f64).unwrap();
+            }
+        }
+
+        // The first pad of the compositor is the background, so start above it
+        for (idx, (peer_id, tile)) in tiles.into_iter().enumerate() {
+            let pad = &self.pads[&peer_id];
+            let rect = match video_size(pad) {
+                Some((video_width, video_height)) => tile.fit(video_width, video_height),
+                None => tile,
+            };
+
+            pad.set_property("xpos", &rect.x).unwrap();
+            pad.set_property("ypos", &rect.y).unwrap();
+            pad.set_property("width", &rect.width).unwrap();
+            pad.set_property("height", &rect.height).unwrap();
+            pad.set_property("alpha", &1.0f64).unwrap();
+            pad.set_property("zorder", &(idx as u32 + 1)).unwrap();
+        }
+    }
+}
+
+// Size of the video on a compositor pad once known. The videos are converted to square pixels
+// before, so that the aspect ratio only depends on this
+fn video_size(pad: &gst::Pad) -> Option<(i32, i32)> {
+    let caps = pad.get_current_caps()?;
+    let s = caps.get_structure(0)?;
+
+    Some((
+        s.get_some::<i32>("width").ok()?,
+        s.get_some::<i32>("height").ok()?,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OUTPUT: Rect = Rect {
+        x: 0,
+        y: 0,
+        width: 1280,
+        height: 720,
+    };
+
+    fn rect(x: i32, y: i32, width: i32, height: i32) -> Rect {
+        Rect {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    fn peers(count: u32) -> Vec<u32> {
+        (1..=count).collect()
+    }
+
+    fn tiles(layout: Layout, count: u32, focus: Option<u32>) -> Vec<(u32, Rect)> {
+        layout.tiles(&peers(count), focus, OUTPUT.width, OUTPUT.height)
+    }
+
+    fn inside(inner: Rect, outer: Rect) -> bool {
+        inner.x >= outer.x
+            && inner.y >= outer.y
+            && inner.x + inner.width <= outer.x + outer.width
+            && inner.y + inner.height <= outer.y + outer.height
+    }
+
+    fn overlap(a: Rect, b: Rect) -> bool {
+        a.x < b.x + b.width && b.x < a.x + a.width && a.y < b.y + b.height && b.y < a.y + a.height
+    }
+
+    #[test]
+    fn fit_video() {
+        // Pillarboxed, letterboxed and exactly fitting
+        assert_eq!(OUTPUT.fit(640, 480), rect(160, 0, 960, 720));
+        assert_eq!(rect(0, 0, 640, 720).fit(1280, 720), rect(0, 180, 640, 360));
+        assert_eq!(
+            rect(100, 50, 320, 180).fit(1920, 1080),
+            rect(100, 50, 320, 180)
+        );
+
+        // Unknown video sizes fill the whole rectangle
+        assert_eq!(OUTPUT.fit(0, 0), OUTPUT);
+    }
+
+    #[test]
+    fn grid_tiles() {
+        assert_eq!(tiles(Layout::Grid, 0, None), vec![]);
+        assert_eq!(tiles(Layout::Grid, 1, None), vec![(1, OUTPUT)]);
+        assert_eq!(
+            tiles(Layout::Grid, 2, None),
+            vec![(1, rect(0, 0, 640, 720)), (2, rect(640, 0, 640, 720))]
+        );
+
+        // The last row is centered
+        assert_eq!(
+            tiles(Layout::Grid, 5, None),
+            vec![
+                (1, rect(0, 0, 426, 360)),
+                (2, rect(426, 0, 426, 360)),
+                (3, rect(852, 0, 426, 360)),
+                (4, rect(213, 360, 426, 360)),
+                (5, rect(639, 360, 426, 360)),
+            ]
+        );
+
+        let grid = tiles(Layout::Grid, 17, None);
+        assert_eq!(grid.len(), 17);
+        assert_eq!(grid[0], (1, rect(0, 0, 256, 180)));
+        assert_eq!(grid[15], (16, rect(384, 540, 256, 180)));
+        assert_eq!(grid[16], (17, rect(640, 540, 256, 180)));
+        for (idx, (_, a)) in grid.iter().enumerate() {
+            assert!(inside(*a, OUTPUT));
+            assert!(grid[idx + 1..].iter().all(|(_, b)| !overlap(*a, *b)));
+        }
+    }
+
+    #[test]
+    fn speaker_tiles() {
+        assert_eq!(tiles(Layout::Speaker, 1, Some(1)), vec![(1, OUTPUT)]);
+
+        // Without a focused peer the first one is large
+        assert_eq!(
+            tiles(Layout::Speaker, 2, None),
+            vec![(1, rect(0, 0, 1280, 540)), (2, rect(0, 540, 1280, 180))]
+        );
+        assert_eq!(
+            tiles(Layout::Speaker, 5, Some(3)),
+            vec![
+                (3, rect(0, 0, 1280, 540)),
+                (1, rect(0, 540, 320, 180)),
+                (2, rect(320, 540, 320, 180)),
+                (4, rect(640, 540, 320, 180)),
+                (5, rect(960, 540, 320, 180)),
+            ]
+        );
+    }
+
+    #[test]
+    fn picture_in_picture_tiles() {
+        assert_eq!(tiles(Layout::PictureInPicture, 1, None), vec![(1, OUTPUT)]);
+        assert_eq!(
+            tiles(Layout::PictureInPicture, 2, Some(2)),
+            vec![(2, OUTPUT), (1, rect(960, 540, 320, 180))]
+        );
+        assert_eq!(
+            tiles(Layout::PictureInPicture, 5, Some(3)),
+            vec![
+                (3, OUTPUT),
+                (1, rect(960, 540, 320, 180)),
+                (2, rect(640, 540, 320, 180)),
+                (4, rect(320, 540, 320, 180)),
+                (5, rect(0, 540, 320, 180)),
+            ]
+        );
+
+        // Up to 16 small pictures have a quarter of the output size, more get smaller instead of
+        // overlapping
+        for count in &[17, 18, 30] {
+            let pip = tiles(Layout::PictureInPicture, *count, None);
+            assert_eq!(pip.len(), *count as usize);
+            assert_eq!(pip[0], (1, OUTPUT));
+
+            let small = &pip[1..];
+            if *count == 17 {
+                assert_eq!(small[15], (17, rect(0, 0, 320, 180)));
+            } else {
+                assert_eq!(small[0], (2, rect(1024, 576, 256, 144)));
+            }
+            for (idx, (_, a)) in small.iter().enumerate() {
+                assert!(inside(*a, OUTPUT));
+                assert!(small[idx + 1..].iter().all(|(_, b)| !overlap(*a, *b)));
+            }
+        }
+    }
+
+    #[test]
+    fn custom_tiles() {
+        let layout = Layout::Custom(
+            PathBuf::from("layout.json"),
+            vec![
+                Region {
+                    x: 0.0,
+                    y: 0.0,
+                    width: 0.5,
+                    height: 1.0,
+                    peer_id: None,
+                },
+                Region {
+                    x: 0.5,
+                    y: 0.0,
+                    width: 0.5,
+                    height: 1.0,
+                    peer_id: Some(1),
+                },
+            ],
+        );
+
+        // Pinned regions are kept for their peer, and peers without a region are not shown
+        assert_eq!(
+            tiles(layout.clone(), 1, None),
+            vec![(1, rect(640, 0, 640, 720))]
+        );
+        assert_eq!(
+            tiles(layout, 5, None),
+            vec![(2, rect(0, 0, 640, 720)), (1, rect(640, 0, 640, 720))]
+        );
+    }
+}
@@ -0,0 +1,355 @@
+// Outputs of the composited room besides local playback
+//
+// Every output gets the composited video and the mix of all peers' audio from the tees behind the
+// mixers and encodes them on its own, with H.264 and Opus or AAC depending on what the target
+// supports.
+
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use async_std::future;
+use futures::channel::oneshot;
+
+use gst::prelude::*;
+
+use anyhow::{anyhow, bail};
+
+use crate::record::Container;
+
+// Where an output is written or streamed to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Target {
+    File(PathBuf, Container),
+    Rtmp(String),
+    Srt(String),
+    // The path of the playlist, the segments are written next to it
+    Hls(PathBuf),
+}
+
+impl std::str::FromStr for Target {
+    type Err = anyhow::Error;
+
+    // An rtmp://, rtmps:// or srt:// URL or the path of a .mkv, .mp4 or .m3u8 file
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.starts_with("rtmp://") || s.starts_with("rtmps://") {
+            return Ok(Target::Rtmp(s.to_string()));
+        } else if s.starts_with("srt://") {
+            return Ok(Target::Srt(s.to_string()));
+        }
+
+        let path = PathBuf::from(s);
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("mkv") => Ok(Target::File(path, Container::Mkv)),
+            Some("mp4") => Ok(Target::File(path, Container::Mp4)),
+            Some("m3u8") => Ok(Target::Hls(path)),
+            _ => bail!(
+                "Invalid output: {}. Use an rtmp:// or srt:// URL or a .mkv, .mp4 or .m3u8 file",
+                s
+            ),
+        }
+    }
+}
+
+impl std::fmt::Display for Target {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Target::File(path, _) | Target::Hls(path) => write!(f, "{}", path.display()),
+            Target::Rtmp(url) | Target::Srt(url) => write!(f, "{}", url),
+        }
+    }
+}
+
+impl Target {
+    // Muxer and sink for the target, named mux and sink
+    fn description(&self) -> &'static str {
+        match self {
+            Target::File(_, Container::Mkv) => "matroskamux name=mux ! filesink name=sink",
+            // Write fragments so that the file is usable even if we don't finish cleanly
+            Target::File(_, Container::Mp4) => {
+                "mp4mux name=mux fragment-duration=1000 ! filesink name=sink"
+            }
+            Target::Rtmp(_) => "flvmux name=mux streamable=true ! rtmpsink name=sink",
+            Target::Srt(_) => "mpegtsmux name=mux ! srtsink name=sink",
+            Target::Hls(_) => "mpegtsmux name=mux ! hlssink name=sink target-duration=2",
+        }
+    }
+
+    // FLV and HLS players generally only support AAC
+    fn audio_encoder(&self) -> &'static str {
+        match self {
+            Target::Rtmp(_) | Target::Hls(_) => "avenc_aac",
+            Target::File(..) | Target::Srt(_) => "opusenc",
+        }
+    }
+
+    // Set where the sink writes to
+    fn configure_sink(&self, sink: &gst::Element) -> Result<(), anyhow::Error> {
+        match self {
+            Target::File(path, _) => {
+                let location = path
+                    .to_str()
+                    .ok_or_else(|| anyhow!("Invalid output path {}", path.display()))?;
+                sink.set_property("location", &location)?;
+            }
+            Target::Rtmp(url) => sink.set_property("location", url)?,
+            Target::Srt(url) => sink.set_property("uri", url)?,
+            Target::Hls(path) => {
+                let (playlist, segments) = hls_locations(path)?;
+                sink.set_property("playlist-location", &playlist)?;
+                sink.set_property("location", &segments)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // GStreamer plugins needed for this output
+    pub fn plugins(&self) -> Vec<&'static str> {
+        let mut needed = vec!["coreelements", "x264", "videoparsersbad"];
+        match self {
+            Target::File(_, container) => needed.extend(container.plugins()),
+            Target::Rtmp(_) => needed.extend(&["flv", "rtmp"]),
+            Target::Srt(_) => needed.extend(&["mpegtsmux", "srt"]),
+            Target::Hls(_) => needed.extend(&["mpegtsmux", "hls"]),
+        }
+        match self {
+            Target::Rtmp(_) | Target::Hls(_) => needed.push("libav"),
+            Target::File(..) | Target::Srt(_) => needed.push("opus"),
+        }
+
+        needed
+    }
+}
+
+// Locations of the HLS playlist and of its segments, which are named like the playlist with a
+// sequence number, e.g. room-00001.ts next to room.m3u8
+fn hls_locations(path: &Path) -> Result<(String, String), anyhow::Error> {
+    let invalid = || anyhow!("Invalid output path {}", path.display());
+
+    let playlist = path.to_str().ok_or_else(invalid)?;
+    let stem = path
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .ok_or_else(invalid)?;
+    let segments = path.with_file_name(format!("{}-%05d.ts", stem));
+
+    Ok((
+        playlist.to_string(),
+        segments.to_str().ok_or_else(invalid)?.to_string(),
+    ))
+}
+
+// An output added to the pipeline
+#[derive(Debug)]
+pub struct Output {
+    target: Target,
+    bin: gst::Bin,
+    // The request pads of the tees the output is linked to
+    tee_pads: Vec<gst::Pad>,
+}
+
+impl Output {
+    // Add the output to the pipeline and link it to the tees with the composited video and the
+    // mixed audio
+    pub fn new(
+        target: &Target,
+        pipeline: &gst::Pipeline,
+        video_tee: &gst::Element,
+        audio_tee: &gst::Element,
+    ) -> Result<Output, anyhow::Error> {
+        let bin = gst::parse_bin_from_description(
+            &format!(
+                "queue name=video-queue ! videoconvert ! \
+                 x264enc tune=zerolatency key-int-max=60 ! h264parse ! mux. \
+                 queue name=audio-queue ! audioconvert ! audioresample ! {audio_encoder} ! mux. \
+                 {mux}",
+                audio_encoder = target.audio_encoder(),
+                mux = target.description(),
+            ),
+            false,
+        )?;
+        target.configure_sink(&bin.get_by_name("sink").unwrap())?;
+        pipeline.add(&bin)?;
+
+        let mut tee_pads = Vec::new();
+        for (tee, media) in &[(video_tee, "video"), (audio_tee, "audio")] {
+            let queue = bin.get_by_name(&format!("{}-queue", media)).unwrap();
+            let sinkpad = gst::GhostPad::with_target(
+                Some(&format!("{}_sink", media)),
+                &queue.get_static_pad("sink").unwrap(),
+            )?;
+            bin.add_pad(&sinkpad)?;
+            let tee_pad = tee.get_request_pad("src_%u").unwrap();
+            tee_pad.link(&sinkpad)?;
+            tee_pads.push(tee_pad);
+        }
+
+        println!("Writing the composited room to {}", target);
+
+        Ok(Output {
+            target: target.clone(),
+            bin,
+            tee_pads,
+        })
+    }
+
+    // Finish the output by sending EOS into it and waiting until it reached the sink, then remove
+    // it from the pipeline. Anything still coming from the mixers is dropped from then on
+    pub async fn finish(&self, pipeline: &gst::Pipeline) -> Result<(), anyhow::Error> {
+        let (eos_tx, eos_rx) = oneshot::channel();
+        let eos_tx = Mutex::new(Some(eos_tx));
+        let sink_pad = self
+            .bin
+            .get_by_name("sink")
+            .unwrap()
+            .get_static_pad("sink")
+            .unwrap();
+        sink_pad.add_probe(gst::PadProbeType::EVENT_DOWNSTREAM, move |_pad, info| {
+            if let Some(gst::PadProbeData::Event(ref event)) = info.data {
+                if event.get_type() == gst::EventType::Eos {
+                    if let Some(eos_tx) = eos_tx.lock().unwrap().take() {
+                        let _ = eos_tx.send(());
+                    }
+                }
+            }
+
+            gst::PadProbeReturn::Ok
+        });
+
+        for pad in self.bin.get_sink_pads() {
+            if let Some(peer) = pad.get_peer() {
+                peer.add_probe(
+                    gst::PadProbeType::BUFFER | gst::PadProbeType::BUFFER_LIST,
+                    |_pad, _info| gst::PadProbeReturn::Drop,
+                );
+            }
+            pad.send_event(gst::event::Eos::new());
+        }
+
+        let finished = future::timeout(Duration::from_secs(5), eos_rx)
+            .await
+            .map_err(|_| anyhow!("Timed out finishing {}", self.target))
+            .and_then(|res| res.map_err(|_| anyhow!("{} was not finished", self.target)));
+
+        // Block the tees shortly for releasing their pads, then remove the output gracefully
+        for tee_pad in &self.tee_pads {
+            let tee = tee_pad.get_parent_element().unwrap();
+            let tee_sinkpad = tee.get_static_pad("sink").unwrap();
+            let block = tee_sinkpad
+                .add_probe(gst::PadProbeType::BLOCK_DOWNSTREAM, |_pad, _info| {
+                    gst::PadProbeReturn::Ok
+                })
+                .unwrap();
+
+            if let Some(sinkpad) = tee_pad.get_peer() {
+                let _ = tee_pad.unlink(&sinkpad);
+            }
+            tee.release_request_pad(tee_pad);
+            tee_sinkpad.remove_probe(block);
+        }
+        let _ = pipeline.remove(&self.bin);
+        let _ = self.bin.set_state(gst::State::Null);
+
+        finished?;
+        println!("Finished {}", self.target);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::str::FromStr;
+
+    #[test]
+    fn parse_targets() {
+        assert_eq!(
+            Target::from_str("rtmp://localhost/live/room").unwrap(),
+            Target::Rtmp(String::from("rtmp://localhost/live/room"))
+        );
+        assert_eq!(
+            Target::from_str("rtmps://example.com/live/key").unwrap(),
+            Target::Rtmp(String::from("rtmps://example.com/live/key"))
+        );
+        assert_eq!(
+            Target::from_str("srt://127.0.0.1:7001?mode=caller").unwrap(),
+            Target::Srt(String::from("srt://127.0.0.1:7001?mode=caller"))
+        );
+        assert_eq!(
+            Target::from_str("room.mkv").unwrap(),
+            Target::File(PathBuf::from("room.mkv"), Container::Mkv)
+        );
+        assert_eq!(
+            Target::from_str("/tmp/room.mp4").unwrap(),
+            Target::File(PathBuf::from("/tmp/room.mp4"), Container::Mp4)
+        );
+        assert_eq!(
+            Target::from_str("hls/room.m3u8").unwrap(),
+            Target::Hls(PathBuf::from("hls/room.m3u8"))
+        );
+
+        assert!(Target::from_str("room.webm").is_err());
+        assert!(Target::from_str("room").is_err());
+        assert!(Target::from_str("http://localhost/room.m3u8.txt").is_err());
+        assert!(Target::from_str("").is_err());
+    }
+
+    #[test]
+    fn target_plugins() {
+        assert_eq!(
+            Target::Rtmp(String::from("rtmp://localhost/live")).plugins(),
+            vec![
+                "coreelements",
+                "x264",
+                "videoparsersbad",
+                "flv",
+                "rtmp",
+                "libav"
+            ]
+        );
+        assert_eq!(
+            Target::Srt(String::from("srt://localhost:7001")).plugins(),
+            vec![
+                "coreelements",
+                "x264",
+                "videoparsersbad",
+                "mpegtsmux",
+                "srt",
+                "opus"
+            ]
+        );
+        assert_eq!(
+            Target::File(PathBuf::from("room.mp4"), Container::Mp4).plugins(),
+            vec!["coreelements", "x264", "videoparsersbad", "isomp4", "opus"]
+        );
+        assert_eq!(
+            Target::Hls(PathBuf::from("room.m3u8")).plugins(),
+            vec![
+                "coreelements",
+                "x264",
+                "videoparsersbad",
+                "mpegtsmux",
+                "hls",
+                "libav"
+            ]
+        );
+    }
+
+    #[test]
+    fn hls_segments_next_to_playlist() {
+        assert_eq!(
+            hls_locations(Path::new("/srv/hls/room.m3u8")).unwrap(),
+            (
+                String::from("/srv/hls/room.m3u8"),
+                String::from("/srv/hls/room-%05d.ts")
+            )
+        );
+        assert_eq!(
+            hls_locations(Path::new("room.m3u8")).unwrap(),
+            (String::from("room.m3u8"), String::from("room-%05d.ts"))
+        );
+    }
+}
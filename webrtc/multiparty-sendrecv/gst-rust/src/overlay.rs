@@ -0,0 +1,235 @@
+// Name labels and status badges on the peers' videos
+//
+// Two textoverlay elements in each peer's video branch show the peer's name at the bottom left and
+// badges for a muted microphone and a poor connection at the top right. Both texts are Pango markup.
+
+use gst::prelude::*;
+
+use webrtc_common::{Stats, StatsKind};
+
+use anyhow::{anyhow, Context};
+
+// The overlay elements in gst-launch syntax, to be placed in the video branch of a peer
+pub const DESCRIPTION: &str = "textoverlay name=label valignment=bottom halignment=left \
+                               shaded-background=true font-desc=\"Sans 24\" ! \
+                               textoverlay name=badges valignment=top halignment=right \
+                               font-desc=\"Sans 24\"";
+
+// Loss in percent of the packets, jitter and round trip time in seconds above which a connection
+// counts as poor
+const POOR_LOSS: f64 = 5.0;
+const POOR_JITTER: f64 = 0.05;
+const POOR_ROUND_TRIP_TIME: f64 = 0.5;
+
+// A display name for a peer on the command line, e.g. 1234=Alice
+pub fn parse_peer_name(s: &str) -> Result<(u32, String), anyhow::Error> {
+    let mut parts = s.splitn(2, '=');
+    let peer_id = parts
+        .next()
+        .unwrap()
+        .parse::<u32>()
+        .with_context(|| format!("Invalid peer id in {}", s))?;
+    let name = parts
+        .next()
+        .ok_or_else(|| anyhow!("Invalid peer name {}, use ID=NAME", s))?;
+
+    Ok((peer_id, name.to_string()))
+}
+
+// Judges the connection from the packet loss, jitter and round trip time reported in the
+// statistics. The loss is only counted since the previous statistics
+#[derive(Debug, Default)]
+struct ConnectionQuality {
+    packets_received: u64,
+    packets_lost: i64,
+}
+
+impl ConnectionQuality {
+    fn is_poor(&mut self, stats: &[Stats]) -> bool {
+        let mut packets_received = 0;
+        let mut packets_lost = 0;
+        let mut jitter = 0.0f64;
+        let mut round_trip_time = 0.0f64;
+
+        for stats in stats {
+            match stats.kind {
+                StatsKind::InboundRtp(ref inbound) => {
+                    packets_received += inbound.packets_received.unwrap_or(0);
+                    packets_lost += inbound.packets_lost.unwrap_or(0);
+                    jitter = jitter.max(inbound.jitter.unwrap_or(0.0));
+                }
+                StatsKind::RemoteInboundRtp(ref remote_inbound) => {
+                    round_trip_time =
+                        round_trip_time.max(remote_inbound.round_trip_time.unwrap_or(0.0));
+                }
+                _ => (),
+            }
+        }
+
+        let received = packets_received.saturating_sub(self.packets_received);
+        let lost = (packets_lost - self.packets_lost).max(0) as u64;
+        self.packets_received = packets_received;
+        self.packets_lost = packets_lost;
+
+        let loss = if received + lost > 0 {
+            100.0 * lost as f64 / (received + lost) as f64
+        } else {
+            0.0
+        };
+
+        loss > POOR_LOSS || jitter > POOR_JITTER || round_trip_time > POOR_ROUND_TRIP_TIME
+    }
+}
+
+// What is shown on the video of one peer
+#[derive(Debug)]
+pub struct Overlay {
+    name: String,
+    muted: bool,
+    poor_connection: bool,
+    quality: ConnectionQuality,
+}
+
+impl Overlay {
+    pub fn new(name: &str) -> Overlay {
+        Overlay {
+            name: name.to_string(),
+            muted: false,
+            poor_connection: false,
+            quality: ConnectionQuality::default(),
+        }
+    }
+
+    pub fn is_poor_connection(&self) -> bool {
+        self.poor_connection
+    }
+
+    // Update the texts of the overlay elements in the bin, if the video arrived already
+    pub fn apply(&self, bin: &gst::Bin) {
+        if let Some(label) = bin.get_by_name("label") {
+            label
+                .set_property("text", &glib::markup_escape_text(&self.name).as_str())
+                .unwrap();
+        }
+
+        if let Some(badges) = bin.get_by_name("badges") {
+            let mut text = Vec::new();
+            if self.muted {
+                text.push("<span background=\"gray\"> muted </span>");
+            }
+            if self.poor_connection {
+                text.push("<span background=\"red\"> connection poor </span>");
+            }
+            badges.set_property("text", &text.join(" ")).unwrap();
+        }
+    }
+
+    // Show whether the peer's audio is muted and return whether this changed
+    pub fn set_muted(&mut self, bin: &gst::Bin, muted: bool) -> bool {
+        if self.muted == muted {
+            return false;
+        }
+
+        self.muted = muted;
+        self.apply(bin);
+
+        true
+    }
+
+    // Judge the connection from new statistics and return whether this changed
+    pub fn update_connection(&mut self, bin: &gst::Bin, stats: &[Stats]) -> bool {
+        let poor_connection = self.quality.is_poor(stats);
+        if self.poor_connection == poor_connection {
+            return false;
+        }
+
+        self.poor_connection = poor_connection;
+        self.apply(bin);
+
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use webrtc_common::{InboundRtp, RemoteInboundRtp};
+
+    fn inbound(packets_received: u64, packets_lost: i64, jitter: f64) -> Stats {
+        Stats {
+            id: "inbound-rtp_1234".into(),
+            timestamp: 0.0,
+            kind: StatsKind::InboundRtp(InboundRtp {
+                ssrc: Some(1234),
+                packets_received: Some(packets_received),
+                bytes_received: None,
+                packets_lost: Some(packets_lost),
+                jitter: Some(jitter),
+                nack_count: None,
+                pli_count: None,
+                fir_count: None,
+                frames_decoded: None,
+                frames_dropped: None,
+            }),
+        }
+    }
+
+    #[test]
+    fn parse_peer_names() {
+        assert_eq!(
+            parse_peer_name("1234=Alice").unwrap(),
+            (1234, String::from("Alice"))
+        );
+        // Only split at the first =
+        assert_eq!(
+            parse_peer_name("1234=Alice=Bob").unwrap(),
+            (1234, String::from("Alice=Bob"))
+        );
+        assert_eq!(
+            parse_peer_name("1234").unwrap_err().to_string(),
+            "Invalid peer name 1234, use ID=NAME"
+        );
+        assert_eq!(
+            parse_peer_name("alice=Alice").unwrap_err().to_string(),
+            "Invalid peer id in alice=Alice"
+        );
+        assert!(parse_peer_name("=Alice").is_err());
+    }
+
+    #[test]
+    fn poor_connection_from_loss() {
+        let mut quality = ConnectionQuality::default();
+
+        // 1% loss since the start
+        assert!(!quality.is_poor(&[inbound(990, 10, 0.01)]));
+        // 10% of the packets since the previous statistics are lost
+        assert!(quality.is_poor(&[inbound(1080, 20, 0.01)]));
+        // And none since then, even though the total loss is still above 1%
+        assert!(!quality.is_poor(&[inbound(1180, 20, 0.01)]));
+        // No new packets at all
+        assert!(!quality.is_poor(&[inbound(1180, 20, 0.01)]));
+        // Duplicates make the lost count go down, which isn't negative loss
+        assert!(!quality.is_poor(&[inbound(1280, 15, 0.01)]));
+    }
+
+    #[test]
+    fn poor_connection_from_jitter_and_round_trip_time() {
+        let mut quality = ConnectionQuality::default();
+        assert!(quality.is_poor(&[inbound(1000, 0, 0.1)]));
+
+        let mut quality = ConnectionQuality::default();
+        let remote = Stats {
+            id: "rtp-remote-inbound-stream-stats_5678".into(),
+            timestamp: 0.0,
+            kind: StatsKind::RemoteInboundRtp(RemoteInboundRtp {
+                ssrc: Some(5678),
+                packets_lost: None,
+                fraction_lost: None,
+                jitter: None,
+                round_trip_time: Some(0.8),
+            }),
+        };
+        assert!(quality.is_poor(&[inbound(1000, 0, 0.01), remote]));
+    }
+}
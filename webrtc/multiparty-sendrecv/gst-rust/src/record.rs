@@ -0,0 +1,290 @@
+// Recording of the streams received from every peer into a separate file
+//
+// Every incoming stream goes through a tee in the peer's bin: one branch is decoded for the mixers
+// or forwarded to the other peers as before, the other one is depayloaded into the muxer of the
+// peer's recording. Only codecs the container can't store are decoded and re-encoded.
+
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use async_std::future;
+use futures::channel::oneshot;
+
+use gst::prelude::*;
+
+use anyhow::{anyhow, bail, Context};
+
+// Container of the recordings
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Container {
+    Mkv,
+    Mp4,
+}
+
+impl std::str::FromStr for Container {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "mkv" => Ok(Container::Mkv),
+            "mp4" => Ok(Container::Mp4),
+            _ => bail!("Invalid container: {}. Use either mkv or mp4", s),
+        }
+    }
+}
+
+impl Container {
+    fn extension(self) -> &'static str {
+        match self {
+            Container::Mkv => "mkv",
+            Container::Mp4 => "mp4",
+        }
+    }
+
+    // GStreamer plugins needed for recording into this container
+    pub fn plugins(self) -> &'static [&'static str] {
+        match self {
+            Container::Mkv => &["matroska"],
+            Container::Mp4 => &["isomp4"],
+        }
+    }
+}
+
+// The recording of one peer
+#[derive(Debug)]
+pub struct Recording {
+    path: PathBuf,
+    mux: gst::Element,
+}
+
+impl Recording {
+    // Add a muxer and file sink to the peer's bin, writing to a file in dir named by the peer's id
+    // and the time it joined
+    pub fn new(
+        bin: &gst::Bin,
+        dir: &Path,
+        container: Container,
+        peer_id: u32,
+    ) -> Result<Recording, anyhow::Error> {
+        let joined = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_secs())
+            .unwrap_or(0);
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("Can't create recording directory {}", dir.display()))?;
+        let path = dir.join(format!(
+            "peer-{}-{}.{}",
+            peer_id,
+            joined,
+            container.extension()
+        ));
+
+        let mux = match container {
+            Container::Mkv => gst::ElementFactory::make("matroskamux", Some("record-mux"))?,
+            Container::Mp4 => {
+                let mux = gst::ElementFactory::make("mp4mux", Some("record-mux"))?;
+                // Write fragments so that the file is usable even if we don't finish cleanly
+                mux.set_property("fragment-duration", &1000u32)?;
+                mux
+            }
+        };
+
+        let filesink = gst::ElementFactory::make("filesink", None)?;
+        filesink.set_property(
+            "location",
+            &path
+                .to_str()
+                .ok_or_else(|| anyhow!("Invalid recording path {}", path.display()))?,
+        )?;
+        // The pipeline is already playing, don't wait for the peer's streams to preroll
+        filesink.set_property("async", &false)?;
+
+        bin.add_many(&[&mux, &filesink])?;
+        mux.link(&filesink)?;
+
+        println!("Recording peer {} to {}", peer_id, path.display());
+
+        Ok(Recording { path, mux })
+    }
+
+    // Split up the incoming stream with these caps that would be linked to sinkpad, and record it
+    // from another branch. Returns the pad the stream has to be linked to instead
+    pub fn add_stream(
+        &self,
+        bin: &gst::Bin,
+        caps: &gst::Caps,
+        sinkpad: &gst::Pad,
+    ) -> Result<gst::Pad, anyhow::Error> {
+        let s = caps.get_structure(0).unwrap();
+        let media = s
+            .get::<&str>("media")
+            .expect("Invalid type")
+            .ok_or_else(|| anyhow!("no media type in caps {:?}", caps))?;
+        let encoding_name = s
+            .get::<&str>("encoding-name")
+            .expect("Invalid type")
+            .ok_or_else(|| anyhow!("no encoding name in caps {:?}", caps))?;
+        let reencode = match media {
+            "video" => "videoconvert ! x264enc tune=zerolatency ! h264parse",
+            "audio" => "audioconvert ! audioresample ! opusenc",
+            _ => bail!("Can't record {} streams", media),
+        };
+
+        let template = self
+            .mux
+            .get_pad_template(&format!("{}_%u", media))
+            .ok_or_else(|| anyhow!("Can't record {} streams", media))?;
+        let template_caps = template
+            .get_property("caps")?
+            .get::<gst::Caps>()
+            .expect("Invalid type")
+            .unwrap();
+
+        let description = match passthrough(encoding_name, &template_caps) {
+            Some(depay) => format!("queue ! {}", depay),
+            None => {
+                println!(
+                    "Can't record {} without re-encoding, re-encoding it",
+                    encoding_name
+                );
+                format!("queue ! decodebin ! {}", reencode)
+            }
+        };
+
+        let branch = gst::parse_bin_from_description(&description, true)?;
+        bin.add(&branch)?;
+        branch
+            .sync_state_with_parent()
+            .with_context(|| format!("can't start recording of stream {:?}", caps))?;
+
+        let mux_pad = self
+            .mux
+            .get_request_pad(&format!("{}_%u", media))
+            .ok_or_else(|| anyhow!("Can't get {} pad from muxer", media))?;
+        branch
+            .get_static_pad("src")
+            .unwrap()
+            .link(&mux_pad)
+            .with_context(|| format!("can't link stream {:?} to the muxer", caps))?;
+
+        let split = gst::ElementFactory::make("tee", None)?;
+        split.set_property("allow-not-linked", &true)?;
+        bin.add(&split)?;
+        split.sync_state_with_parent()?;
+        split.get_request_pad("src_%u").unwrap().link(sinkpad)?;
+        split
+            .get_request_pad("src_%u")
+            .unwrap()
+            .link(&branch.get_static_pad("sink").unwrap())?;
+
+        Ok(split.get_static_pad("sink").unwrap())
+    }
+
+    // Finish the recording by sending EOS to the muxer and waiting until it's written out.
+    // Anything still arriving from the peer is dropped from then on
+    pub async fn finish(&self) -> Result<(), anyhow::Error> {
+        let mux_pads = self.mux.get_sink_pads();
+        if mux_pads.is_empty() {
+            println!("Nothing was recorded to {}", self.path.display());
+            return Ok(());
+        }
+
+        let (eos_tx, eos_rx) = oneshot::channel();
+        let eos_tx = Mutex::new(Some(eos_tx));
+        let filesink_pad = self.mux.get_static_pad("src").unwrap().get_peer().unwrap();
+        filesink_pad.add_probe(gst::PadProbeType::EVENT_DOWNSTREAM, move |_pad, info| {
+            if let Some(gst::PadProbeData::Event(ref event)) = info.data {
+                if event.get_type() == gst::EventType::Eos {
+                    if let Some(eos_tx) = eos_tx.lock().unwrap().take() {
+                        let _ = eos_tx.send(());
+                    }
+                }
+            }
+
+            gst::PadProbeReturn::Ok
+        });
+
+        for pad in mux_pads {
+            if let Some(peer) = pad.get_peer() {
+                peer.add_probe(
+                    gst::PadProbeType::BUFFER | gst::PadProbeType::BUFFER_LIST,
+                    |_pad, _info| gst::PadProbeReturn::Drop,
+                );
+            }
+            pad.send_event(gst::event::Eos::new());
+        }
+
+        future::timeout(Duration::from_secs(5), eos_rx)
+            .await
+            .map_err(|_| anyhow!("Timed out finishing {}", self.path.display()))?
+            .map_err(|_| anyhow!("{} was not finished", self.path.display()))?;
+
+        println!("Finished recording {}", self.path.display());
+
+        Ok(())
+    }
+}
+
+// Depayloader for the RTP encoding name if the muxer pad with these template caps takes the
+// depayloaded stream as is, otherwise it has to be re-encoded
+fn passthrough(encoding_name: &str, template_caps: &gst::Caps) -> Option<&'static str> {
+    depayloader(encoding_name)
+        .filter(|(_, parsed_caps)| {
+            template_caps.can_intersect(&gst::Caps::new_simple(parsed_caps, &[]))
+        })
+        .map(|(depay, _)| depay)
+}
+
+// Depayloader for the RTP encoding name and the caps of the depayloaded stream, to check if the
+// stream can be recorded without decoding it
+fn depayloader(encoding_name: &str) -> Option<(&'static str, &'static str)> {
+    match encoding_name {
+        "VP8" => Some(("rtpvp8depay", "video/x-vp8")),
+        "VP9" => Some(("rtpvp9depay", "video/x-vp9")),
+        "H264" => Some(("rtph264depay ! h264parse", "video/x-h264")),
+        "AV1" => Some(("rtpav1depay ! av1parse", "video/x-av1")),
+        "OPUS" => Some(("rtpopusdepay", "audio/x-opus")),
+        "G722" => Some(("rtpg722depay", "audio/G722")),
+        "PCMU" => Some(("rtppcmudepay", "audio/x-mulaw")),
+        "PCMA" => Some(("rtppcmadepay", "audio/x-alaw")),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::str::FromStr;
+
+    #[test]
+    fn parse_containers() {
+        assert_eq!(Container::from_str("mkv").unwrap(), Container::Mkv);
+        assert_eq!(Container::from_str("mp4").unwrap(), Container::Mp4);
+        assert!(Container::from_str("MKV").is_err());
+        assert!(Container::from_str("webm").is_err());
+        assert_eq!(Container::Mp4.extension(), "mp4");
+    }
+
+    #[test]
+    fn passthrough_or_reencode() {
+        gst::init().unwrap();
+
+        // Like the video template of mp4mux, which can't store VP8
+        let video_caps = gst::Caps::from_str("video/x-h264; video/x-vp9; video/x-av1").unwrap();
+        assert_eq!(
+            passthrough("H264", &video_caps),
+            Some("rtph264depay ! h264parse")
+        );
+        assert_eq!(passthrough("VP9", &video_caps), Some("rtpvp9depay"));
+        assert_eq!(passthrough("VP8", &video_caps), None);
+        // Unknown encodings are always re-encoded
+        assert_eq!(passthrough("H265", &video_caps), None);
+
+        let audio_caps = gst::Caps::from_str("audio/x-opus; audio/x-mulaw").unwrap();
+        assert_eq!(passthrough("OPUS", &audio_caps), Some("rtpopusdepay"));
+        assert_eq!(passthrough("PCMU", &audio_caps), Some("rtppcmudepay"));
+        assert_eq!(passthrough("PCMA", &audio_caps), None);
+    }
+}
@@ -0,0 +1,203 @@
+// Detection of the active speaker from the audio levels of the peers
+//
+// A level element in each peer's audio branch reports its loudness every 100ms. The levels are
+// smoothed, and another peer only takes over as active speaker if it's clearly louder than the
+// current one and the speaker didn't change just before, so that short noises don't switch it.
+// When everybody is silent the last speaker stays active.
+
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+// Weight of a new level in the smoothed level
+const SMOOTHING: f64 = 0.3;
+// Smoothed level in dB above which a peer is considered speaking
+const SPEAKING: f64 = -50.0;
+// How much louder in dB a peer has to be than the active speaker to take over
+const HYSTERESIS: f64 = 6.0;
+// Minimum time between two changes of the active speaker
+const HOLD: Duration = Duration::from_secs(1);
+// Smoothed level in dB below which a peer only sends digital silence, e.g. when muted
+const SILENT: f64 = -90.0;
+// Digital silence is reported as -inf dB, which would never recover in the smoothed level
+const FLOOR: f64 = -100.0;
+
+#[derive(Debug, Default)]
+pub struct SpeakerDetector {
+    // Smoothed level of each peer in dB
+    levels: BTreeMap<u32, f64>,
+    active: Option<u32>,
+    changed: Option<Instant>,
+}
+
+impl SpeakerDetector {
+    pub fn active(&self) -> Option<u32> {
+        self.active
+    }
+
+    // Smoothed level of the peer in dB
+    pub fn level(&self, peer_id: u32) -> Option<f64> {
+        self.levels.get(&peer_id).copied()
+    }
+
+    // Whether the peer only sends digital silence lately
+    pub fn is_silent(&self, peer_id: u32) -> bool {
+        self.level(peer_id).map_or(false, |level| level < SILENT)
+    }
+
+    // Add a new level of the peer in dB and return whether the active speaker changed
+    pub fn update(&mut self, peer_id: u32, level: f64, now: Instant) -> bool {
+        let level = level.max(FLOOR);
+        let smoothed = match self.levels.get(&peer_id) {
+            Some(previous) => previous + SMOOTHING * (level - previous),
+            None => level,
+        };
+        self.levels.insert(peer_id, smoothed);
+
+        if self
+            .changed
+            .map_or(false, |changed| now.duration_since(changed) < HOLD)
+        {
+            return false;
+        }
+
+        let (loudest, loudest_level) = match self
+            .levels
+            .iter()
+            .max_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap())
+        {
+            Some((loudest, loudest_level)) => (*loudest, *loudest_level),
+            None => return false,
+        };
+        if loudest_level < SPEAKING || Some(loudest) == self.active {
+            return false;
+        }
+
+        let takes_over = match self.active.and_then(|active| self.level(active)) {
+            Some(active_level) => {
+                active_level < SPEAKING || loudest_level > active_level + HYSTERESIS
+            }
+            None => true,
+        };
+        if !takes_over {
+            return false;
+        }
+
+        self.active = Some(loudest);
+        self.changed = Some(now);
+
+        true
+    }
+
+    // Forget a peer that left and return whether it was the active speaker
+    pub fn remove_peer(&mut self, peer_id: u32) -> bool {
+        self.levels.remove(&peer_id);
+
+        if self.active == Some(peer_id) {
+            self.active = None;
+            self.changed = None;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn after(start: Instant, millis: u64) -> Instant {
+        start + Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn first_speaker() {
+        let start = Instant::now();
+        let mut detector = SpeakerDetector::default();
+
+        // Quiet peers don't become the active speaker
+        assert!(!detector.update(1, -70.0, start));
+        assert_eq!(detector.active(), None);
+
+        assert!(detector.update(2, -20.0, start));
+        assert_eq!(detector.active(), Some(2));
+        assert!(!detector.update(2, -20.0, after(start, 100)));
+    }
+
+    #[test]
+    fn hysteresis() {
+        let start = Instant::now();
+        let mut detector = SpeakerDetector::default();
+        assert!(detector.update(1, -20.0, start));
+
+        // Slightly louder is not enough to take over
+        for n in 0..20 {
+            assert!(!detector.update(2, -16.0, after(start, 2000 + n * 100)));
+            assert!(!detector.update(1, -20.0, after(start, 2000 + n * 100)));
+        }
+        assert_eq!(detector.active(), Some(1));
+
+        // Clearly louder takes over once the smoothed level got there
+        assert!(!detector.update(2, -10.0, after(start, 4000)));
+        assert!(detector.update(2, -10.0, after(start, 4100)));
+        assert_eq!(detector.active(), Some(2));
+    }
+
+    #[test]
+    fn hold_time() {
+        let start = Instant::now();
+        let mut detector = SpeakerDetector::default();
+        assert!(detector.update(1, -20.0, start));
+
+        // A loud noise just after the last change doesn't switch, but the level is kept
+        assert!(!detector.update(2, 0.0, after(start, 500)));
+        assert_eq!(detector.active(), Some(1));
+        assert_eq!(detector.level(2), Some(0.0));
+
+        assert!(detector.update(2, 0.0, after(start, 1000)));
+        assert_eq!(detector.active(), Some(2));
+
+        assert!(!detector.update(1, 40.0, after(start, 1500)));
+        assert!(!detector.update(1, 40.0, after(start, 1900)));
+        assert!(detector.update(1, 40.0, after(start, 2000)));
+        assert_eq!(detector.active(), Some(1));
+    }
+
+    #[test]
+    fn silence() {
+        let start = Instant::now();
+        let mut detector = SpeakerDetector::default();
+        assert!(detector.update(1, -20.0, start));
+        assert!(!detector.is_silent(1));
+
+        // When everybody is silent the last speaker stays active, and digital silence recovers
+        for n in 0..20 {
+            assert!(!detector.update(1, f64::NEG_INFINITY, after(start, 1000 + n * 100)));
+        }
+        assert_eq!(detector.active(), Some(1));
+        assert!(detector.is_silent(1));
+        assert!(detector.level(1).unwrap() >= FLOOR);
+
+        // Any speaking peer takes over from a silent speaker
+        assert!(detector.update(2, -45.0, after(start, 3000)));
+        assert_eq!(detector.active(), Some(2));
+        assert!(!detector.is_silent(3));
+    }
+
+    #[test]
+    fn remove_peer() {
+        let start = Instant::now();
+        let mut detector = SpeakerDetector::default();
+        assert!(detector.update(1, -20.0, start));
+        assert!(!detector.update(2, -30.0, start));
+
+        assert!(!detector.remove_peer(2));
+        assert_eq!(detector.level(2), None);
+        assert!(detector.remove_peer(1));
+        assert_eq!(detector.active(), None);
+
+        // Without an active speaker there is no hold time
+        assert!(detector.update(3, -20.0, after(start, 100)));
+        assert_eq!(detector.active(), Some(3));
+    }
+}
@@ -0,0 +1,258 @@
+// Detection of stalled peers
+//
+// Only if requested, a peer is stalled if its ICE connection isn't established within
+// --connect-timeout seconds, or if no media arrived from it for --media-timeout seconds while
+// connected. It's then evicted from the call, or with --retry-offer it first gets one more offer
+// with ICE restart and the same time again. Only the impolite side sends that offer, the polite
+// side waits for it. Failures of an established ICE connection are also handled by
+// watch_connection().
+
+use std::time::{Duration, Instant};
+
+use structopt::StructOpt;
+
+#[derive(Debug, Clone, StructOpt)]
+pub struct WatchdogArgs {
+    // Seconds a peer gets to establish its ICE connection, 0 to wait forever
+    #[structopt(long, default_value = "0")]
+    connect_timeout: u64,
+    // Seconds a connected peer may send no media before it's evicted, 0 to never evict it for that.
+    // Peers that are muted might not send any media either
+    #[structopt(long, default_value = "0")]
+    media_timeout: u64,
+    // Send a new offer with ICE restart to a stalled peer once before evicting it
+    #[structopt(long)]
+    retry_offer: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    Fine,
+    // The peer is stalled for the given reason
+    Retry(String),
+    Evict(String),
+}
+
+#[derive(Debug)]
+pub struct Watchdog {
+    connect_timeout: Option<Duration>,
+    media_timeout: Option<Duration>,
+    retry_offer: bool,
+    connected: bool,
+    // Since when the peer is expected to connect or send media, reset by a retry
+    since: Instant,
+    last_media: Option<Instant>,
+    retried: bool,
+    evicted: bool,
+}
+
+impl WatchdogArgs {
+    // Whether peers are checked at all
+    pub fn enabled(&self) -> bool {
+        self.connect_timeout > 0 || self.media_timeout > 0
+    }
+}
+
+fn timeout(secs: u64) -> Option<Duration> {
+    if secs == 0 {
+        None
+    } else {
+        Some(Duration::from_secs(secs))
+    }
+}
+
+impl Watchdog {
+    pub fn new(args: &WatchdogArgs, now: Instant) -> Self {
+        Watchdog {
+            connect_timeout: timeout(args.connect_timeout),
+            media_timeout: timeout(args.media_timeout),
+            retry_offer: args.retry_offer,
+            connected: false,
+            since: now,
+            last_media: None,
+            retried: false,
+            evicted: false,
+        }
+    }
+
+    // The media timeout starts once the peer is connected
+    pub fn set_connected(&mut self, connected: bool, now: Instant) {
+        if connected != self.connected {
+            self.connected = connected;
+            self.since = now;
+        }
+    }
+
+    pub fn media_received(&mut self, now: Instant) {
+        self.last_media = Some(now);
+    }
+
+    // Why the peer is stalled, if it is
+    fn stalled(&self, now: Instant) -> Option<String> {
+        if !self.connected {
+            let timeout = self.connect_timeout?;
+            if now.duration_since(self.since) > timeout {
+                return Some(format!(
+                    "ICE connection not established within {}s",
+                    timeout.as_secs()
+                ));
+            }
+        } else if let Some(timeout) = self.media_timeout {
+            let last_media = self
+                .last_media
+                .map_or(self.since, |last| last.max(self.since));
+            if now.duration_since(last_media) > timeout {
+                return Some(format!("no media received for {}s", timeout.as_secs()));
+            }
+        }
+
+        None
+    }
+
+    // Check the peer, called periodically. A peer is only evicted once, and is retried once per
+    // stall if requested
+    pub fn check(&mut self, now: Instant) -> Verdict {
+        if self.evicted {
+            return Verdict::Fine;
+        }
+
+        let reason = match self.stalled(now) {
+            Some(reason) => reason,
+            None => {
+                if self.connected && self.last_media.map_or(false, |last| last > self.since) {
+                    self.retried = false;
+                }
+                return Verdict::Fine;
+            }
+        };
+
+        if self.retry_offer && !self.retried {
+            self.retried = true;
+            self.since = now;
+            Verdict::Retry(reason)
+        } else {
+            self.evicted = true;
+            Verdict::Evict(reason)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_watchdog(
+        connect_timeout: u64,
+        media_timeout: u64,
+        retry_offer: bool,
+    ) -> (Watchdog, Instant) {
+        let start = Instant::now();
+        let args = WatchdogArgs {
+            connect_timeout,
+            media_timeout,
+            retry_offer,
+        };
+
+        (Watchdog::new(&args, start), start)
+    }
+
+    fn after(start: Instant, secs: u64) -> Instant {
+        start + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn disabled() {
+        let args = WatchdogArgs::from_iter(&["watchdog"]);
+        assert!(!args.enabled());
+
+        let (mut watchdog, start) = new_watchdog(0, 0, false);
+        assert_eq!(watchdog.check(after(start, 3600)), Verdict::Fine);
+        watchdog.set_connected(true, after(start, 3600));
+        assert_eq!(watchdog.check(after(start, 7200)), Verdict::Fine);
+    }
+
+    #[test]
+    fn connect_timeout() {
+        let (mut watchdog, start) = new_watchdog(30, 0, false);
+
+        assert_eq!(watchdog.check(after(start, 30)), Verdict::Fine);
+        assert_eq!(
+            watchdog.check(after(start, 31)),
+            Verdict::Evict("ICE connection not established within 30s".into())
+        );
+        // Only evicted once
+        assert_eq!(watchdog.check(after(start, 32)), Verdict::Fine);
+
+        // Losing the connection starts the timeout again
+        let (mut watchdog, start) = new_watchdog(30, 0, false);
+        watchdog.set_connected(true, after(start, 5));
+        assert_eq!(watchdog.check(after(start, 40)), Verdict::Fine);
+        watchdog.set_connected(false, after(start, 40));
+        assert_eq!(watchdog.check(after(start, 70)), Verdict::Fine);
+        assert!(matches!(
+            watchdog.check(after(start, 71)),
+            Verdict::Evict(_)
+        ));
+    }
+
+    #[test]
+    fn media_timeout() {
+        let (mut watchdog, start) = new_watchdog(30, 10, false);
+        watchdog.set_connected(true, after(start, 5));
+        watchdog.media_received(after(start, 6));
+
+        assert_eq!(watchdog.check(after(start, 16)), Verdict::Fine);
+        assert_eq!(
+            watchdog.check(after(start, 17)),
+            Verdict::Evict("no media received for 10s".into())
+        );
+
+        // Connected peers that never sent media count from when they connected
+        let (mut watchdog, start) = new_watchdog(30, 10, false);
+        watchdog.set_connected(true, after(start, 5));
+        assert_eq!(watchdog.check(after(start, 15)), Verdict::Fine);
+        assert!(matches!(
+            watchdog.check(after(start, 16)),
+            Verdict::Evict(_)
+        ));
+    }
+
+    #[test]
+    fn retry_offer() {
+        let (mut watchdog, start) = new_watchdog(10, 0, true);
+
+        assert_eq!(
+            watchdog.check(after(start, 11)),
+            Verdict::Retry("ICE connection not established within 10s".into())
+        );
+        // The same time again after the retry
+        assert_eq!(watchdog.check(after(start, 21)), Verdict::Fine);
+        assert!(matches!(
+            watchdog.check(after(start, 22)),
+            Verdict::Evict(_)
+        ));
+    }
+
+    #[test]
+    fn retry_again_after_recovery() {
+        let (mut watchdog, start) = new_watchdog(10, 10, true);
+        watchdog.set_connected(true, after(start, 5));
+
+        assert!(matches!(
+            watchdog.check(after(start, 16)),
+            Verdict::Retry(_)
+        ));
+        watchdog.media_received(after(start, 17));
+        assert_eq!(watchdog.check(after(start, 18)), Verdict::Fine);
+
+        // Media arrived after the retry, so the next stall is retried again
+        assert!(matches!(
+            watchdog.check(after(start, 28)),
+            Verdict::Retry(_)
+        ));
+        assert!(matches!(
+            watchdog.check(after(start, 39)),
+            Verdict::Evict(_)
+        ));
+    }
+}
@@ -0,0 +1,324 @@
+// File transfers over a data channel
+//
+// Chat messages are sent as plain strings over their own data channel, like the browser demo does,
+// so any line can be sent as it is. File transfers use a second data channel, with the JSON control
+// messages below as strings and the file contents as binary chunks. The receiver acknowledges each
+// chunk before the next one is sent and confirms whether the SHA-256 checksum of the whole file
+// matches at the end.
+
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use serde_derive::{Deserialize, Serialize};
+
+use anyhow::{anyhow, bail, Context};
+
+// Stay well below the maximum SCTP message size supported by all browsers
+const CHUNK_SIZE: usize = 16 * 1024;
+
+// Labels of the data channels created by the offerer
+pub const CHAT_CHANNEL: &str = "chat";
+pub const FILE_CHANNEL: &str = "file";
+
+// Control messages of the file transfer
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ChannelMessage {
+    FileStart {
+        name: String,
+        size: u64,
+        sha256: String,
+    },
+    Ack {
+        received: u64,
+    },
+    FileDone {
+        ok: bool,
+    },
+}
+
+fn file_checksum(path: &Path) -> Result<String, anyhow::Error> {
+    let mut file = File::open(path)?;
+    let mut checksum = glib::Checksum::new(glib::ChecksumType::Sha256);
+    let mut buffer = vec![0; CHUNK_SIZE];
+
+    loop {
+        let len = file.read(&mut buffer)?;
+        if len == 0 {
+            break;
+        }
+        checksum.update(&buffer[..len]);
+    }
+
+    checksum
+        .get_string()
+        .ok_or_else(|| anyhow!("Can't compute checksum of {}", path.display()))
+}
+
+// Sending side of a file transfer
+#[derive(Debug)]
+pub struct FileSender {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    sha256: String,
+    sent: u64,
+}
+
+impl FileSender {
+    pub fn open(path: &Path) -> Result<Self, anyhow::Error> {
+        let sha256 =
+            file_checksum(path).with_context(|| format!("Can't read file {}", path.display()))?;
+        let file = File::open(path)?;
+        let size = file.metadata()?.len();
+
+        Ok(FileSender {
+            path: path.to_path_buf(),
+            file,
+            size,
+            sha256,
+            sent: 0,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // Message announcing the file to the receiver
+    pub fn start_message(&self) -> ChannelMessage {
+        ChannelMessage::FileStart {
+            name: self
+                .path
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_else(|| String::from("file")),
+            size: self.size,
+            sha256: self.sha256.clone(),
+        }
+    }
+
+    // Read the next chunk once the receiver acknowledged everything sent so far, or return None
+    // if the whole file was sent
+    pub fn next_chunk(&mut self, acked: u64) -> Result<Option<Vec<u8>>, anyhow::Error> {
+        if acked != self.sent {
+            bail!(
+                "Receiver acknowledged {} bytes but we sent {}",
+                acked,
+                self.sent
+            );
+        }
+
+        if self.sent == self.size {
+            return Ok(None);
+        }
+
+        let mut chunk = vec![0; CHUNK_SIZE.min((self.size - self.sent) as usize)];
+        self.file.read_exact(&mut chunk)?;
+        self.sent += chunk.len() as u64;
+
+        Ok(Some(chunk))
+    }
+}
+
+// Receiving side of a file transfer
+#[derive(Debug)]
+pub struct FileReceiver {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    sha256: String,
+    received: u64,
+}
+
+impl FileReceiver {
+    // Create the announced file in dir, without overwriting any existing file. Files larger than
+    // max_size bytes are refused
+    pub fn create(
+        dir: &Path,
+        name: &str,
+        size: u64,
+        sha256: String,
+        max_size: u64,
+    ) -> Result<Self, anyhow::Error> {
+        if size > max_size {
+            bail!(
+                "File {} of {} bytes is larger than the limit of {} bytes",
+                name,
+                size,
+                max_size
+            );
+        }
+
+        let path = Path::new(name)
+            .file_name()
+            .map(|name| dir.join(name))
+            .ok_or_else(|| anyhow!("Invalid file name {}", name))?;
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .with_context(|| format!("Can't create file {}", path.display()))?;
+
+        Ok(FileReceiver {
+            path,
+            file,
+            size,
+            sha256,
+            received: 0,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // Write the next chunk and return the acknowledgement for it
+    pub fn write_chunk(&mut self, data: &[u8]) -> Result<ChannelMessage, anyhow::Error> {
+        if self.received + data.len() as u64 > self.size {
+            bail!("Received more than the announced {} bytes", self.size);
+        }
+
+        self.file.write_all(data)?;
+        self.received += data.len() as u64;
+
+        Ok(ChannelMessage::Ack {
+            received: self.received,
+        })
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.received == self.size
+    }
+
+    // Flush the file and check if its checksum matches the announced one
+    pub fn finish(mut self) -> Result<bool, anyhow::Error> {
+        self.file.flush()?;
+
+        Ok(file_checksum(&self.path)? == self.sha256)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Directory with the files of one test, removed again at its end
+    struct TestDir(PathBuf);
+
+    impl TestDir {
+        fn new(name: &str) -> Self {
+            let dir =
+                std::env::temp_dir().join(format!("webrtc-app-{}-{}", name, std::process::id()));
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(dir.join("send")).unwrap();
+            std::fs::create_dir_all(dir.join("receive")).unwrap();
+
+            TestDir(dir)
+        }
+
+        // Write a file with size bytes to send
+        fn file(&self, size: usize) -> PathBuf {
+            let path = self.0.join("send").join("file.bin");
+            let data = (0..size).map(|idx| (idx % 251) as u8).collect::<Vec<_>>();
+            std::fs::write(&path, &data).unwrap();
+
+            path
+        }
+    }
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    // Pass a control message through JSON like over the data channel
+    fn roundtrip(msg: &ChannelMessage) -> ChannelMessage {
+        serde_json::from_str(&serde_json::to_string(msg).unwrap()).unwrap()
+    }
+
+    // Transfer the file like App does, with corrupt applied to every chunk on the way. Returns
+    // the path of the received file and whether its checksum matched
+    fn transfer(dir: &TestDir, path: &Path, corrupt: impl Fn(&mut Vec<u8>)) -> (PathBuf, bool) {
+        let mut sender = FileSender::open(path).unwrap();
+        let (name, size, sha256) = match roundtrip(&sender.start_message()) {
+            ChannelMessage::FileStart { name, size, sha256 } => (name, size, sha256),
+            msg => panic!("Unexpected message {:?}", msg),
+        };
+        let mut receiver =
+            FileReceiver::create(&dir.0.join("receive"), &name, size, sha256, u64::MAX).unwrap();
+
+        let mut acked = 0;
+        while !receiver.is_complete() {
+            let mut chunk = sender.next_chunk(acked).unwrap().expect("no chunk left");
+            assert!(chunk.len() <= CHUNK_SIZE);
+            corrupt(&mut chunk);
+
+            match roundtrip(&receiver.write_chunk(&chunk).unwrap()) {
+                ChannelMessage::Ack { received } => acked = received,
+                msg => panic!("Unexpected message {:?}", msg),
+            }
+        }
+        assert_eq!(sender.next_chunk(acked).unwrap(), None);
+
+        let received = receiver.path().to_path_buf();
+        (received, receiver.finish().unwrap())
+    }
+
+    #[test]
+    fn transfer_files() {
+        let dir = TestDir::new("transfer-files");
+
+        for size in &[0, 1, CHUNK_SIZE, 2 * CHUNK_SIZE + 123] {
+            let path = dir.file(*size);
+            let (received, ok) = transfer(&dir, &path, |_| ());
+
+            assert!(ok);
+            assert_eq!(received, dir.0.join("receive").join("file.bin"));
+            assert_eq!(
+                std::fs::read(&received).unwrap(),
+                std::fs::read(&path).unwrap()
+            );
+            std::fs::remove_file(&received).unwrap();
+        }
+    }
+
+    #[test]
+    fn detect_checksum_mismatch() {
+        let dir = TestDir::new("checksum-mismatch");
+
+        let path = dir.file(CHUNK_SIZE + 1);
+        let (_, ok) = transfer(&dir, &path, |chunk| chunk[0] ^= 0xff);
+
+        assert!(!ok);
+    }
+
+    #[test]
+    fn reject_invalid_transfers() {
+        let dir = TestDir::new("invalid-transfers");
+        let receive_dir = dir.0.join("receive");
+
+        // Chunks are only sent once everything before was acknowledged
+        let mut sender = FileSender::open(&dir.file(10)).unwrap();
+        assert!(sender.next_chunk(5).is_err());
+        assert_eq!(sender.next_chunk(0).unwrap().unwrap().len(), 10);
+        assert!(sender.next_chunk(0).is_err());
+
+        // Never more than announced
+        let mut receiver = FileReceiver::create(&receive_dir, "a", 1, String::new(), 1).unwrap();
+        assert!(receiver.write_chunk(&[0, 1]).is_err());
+
+        // Existing files are never overwritten
+        assert!(FileReceiver::create(&receive_dir, "a", 1, String::new(), 1).is_err());
+
+        // Nor are files larger than the limit created
+        assert!(FileReceiver::create(&receive_dir, "c", 2, String::new(), 1).is_err());
+        assert!(!receive_dir.join("c").exists());
+
+        // The file is always created in the given directory
+        let receiver = FileReceiver::create(&receive_dir, "../b", 1, String::new(), 1).unwrap();
+        assert_eq!(receiver.path(), receive_dir.join("b"));
+    }
+}
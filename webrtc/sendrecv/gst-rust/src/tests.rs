@@ -0,0 +1,269 @@
+// Loopback test connecting two Apps through a local signalling server
+
+use std::time::Duration;
+
+use structopt::StructOpt;
+
+use async_std::future;
+use async_std::task;
+use futures::channel::oneshot;
+use futures::future::FutureExt;
+use futures::pin_mut;
+use futures::stream;
+
+use gst::prelude::*;
+
+use webrtc_common::{request_stats, Stats};
+use webrtc_signalling::{Server, ServerOptions};
+
+use crate::{check_plugins, run_app, App, Args};
+
+// Options for retransmissions and FEC, with many FEC packets so that some are used quickly
+const RECOVERY: [&str; 5] = ["--nack", "--fec-type", "ulp-red", "--fec-percentage", "50"];
+
+// Network impairment for both Apps of the impaired loopback test, unless replaced by the
+// IMPAIRED_LOOPBACK_ARGS environment variable
+const IMPAIRMENT: [&str; 6] = [
+    "--drop-probability",
+    "0.02",
+    "--delay",
+    "50",
+    "--jitter",
+    "10",
+];
+
+// How long the Apps get for connecting and exchanging media
+const DEADLINE: Duration = Duration::from_secs(30);
+
+// How long the impaired loopback test measures once media flows
+const MEASUREMENT: Duration = Duration::from_secs(10);
+
+// Start a plain signalling server on a random local port and return its URL
+async fn start_server() -> String {
+    let server = Server::bind("127.0.0.1:0", ServerOptions::default())
+        .await
+        .unwrap();
+    let addr = server.local_addr().unwrap();
+    task::spawn(server.run());
+
+    format!("ws://{}", addr)
+}
+
+// Register with the server, optionally start a session with the given peer and create an App that
+// only uses host candidates and fakesinks. Returns the App and its message loop, or fails the test
+// if GStreamer plugins needed by the App are missing
+macro_rules! start_app {
+    ($server:expr, $uid:expr, $extra_args:expr) => {{
+        let server: &str = $server;
+        let mut cmdline = vec!["webrtc-app", "--server", server, "--no-stun", "--fakesink"];
+        cmdline.extend_from_slice($extra_args);
+        let args = Args::from_iter(cmdline);
+        if let Err(err) = check_plugins(&args) {
+            panic!("Can't run test: {}", err);
+        }
+
+        let mut client = webrtc_signalling::connect(server).await.unwrap();
+        client.register($uid).await.unwrap();
+        if let Some(peer_id) = args.peer_id {
+            client.start_session(&peer_id.to_string()).await.unwrap();
+        }
+        let (sig_sink, sig_stream) = client.split();
+
+        let (app, send_gst_msg_rx, send_msg_rx) = App::new(args).unwrap();
+        (
+            app.clone(),
+            run_app(
+                app,
+                send_gst_msg_rx,
+                send_msg_rx,
+                stream::pending(),
+                stream::pending(),
+                sig_sink,
+                sig_stream,
+            ),
+        )
+    }};
+}
+
+// Whether ICE is connected and both video and audio buffers were decoded
+fn has_media(app: &App) -> bool {
+    let ice_state = app
+        .webrtcbin
+        .get_property("ice-connection-state")
+        .unwrap()
+        .get_some::<gst_webrtc::WebRTCICEConnectionState>()
+        .unwrap();
+    let connected = match ice_state {
+        gst_webrtc::WebRTCICEConnectionState::Connected
+        | gst_webrtc::WebRTCICEConnectionState::Completed => true,
+        _ => false,
+    };
+
+    let received_buffers = app.received_buffers.lock().unwrap();
+    connected
+        && received_buffers.get("video").map_or(false, |n| *n > 0)
+        && received_buffers.get("audio").map_or(false, |n| *n > 0)
+}
+
+// Sum of a counter property of all elements of the factory inside webrtcbin
+fn count(app: &App, factory: &str, property: &str) -> u32 {
+    let webrtcbin = app.webrtcbin.clone().downcast::<gst::Bin>().unwrap();
+    let mut elements = webrtcbin.iterate_recurse();
+    let mut sum = 0;
+
+    while let Ok(Some(element)) = elements.next() {
+        if element
+            .get_factory()
+            .map_or(false, |f| f.get_name() == factory)
+        {
+            sum += element
+                .get_property(property)
+                .unwrap()
+                .get_some::<u32>()
+                .unwrap();
+        }
+    }
+
+    sum
+}
+
+// Whether packets were recovered both from FEC and retransmissions
+fn has_recovered(app: &App) -> bool {
+    count(app, "rtpulpfecdec", "recovered") > 0
+        && count(app, "rtprtxreceive", "num-rtx-assoc-packets") > 0
+}
+
+#[test]
+fn loopback() {
+    gst::init().unwrap();
+
+    task::block_on(async {
+        let server = start_server().await;
+
+        let (answerer, answerer_loop) = start_app!(&server, "1", &[]);
+        let (offerer, offerer_loop) = start_app!(&server, "2", &["--peer-id", "1"]);
+
+        let media = future::timeout(DEADLINE, async {
+            while !has_media(&answerer) || !has_media(&offerer) {
+                task::sleep(Duration::from_millis(100)).await;
+            }
+        });
+
+        pin_mut!(answerer_loop, offerer_loop, media);
+        futures::select! {
+            res = media.fuse() => res.expect("no media on both sides before the deadline"),
+            res = answerer_loop.fuse() => panic!("answerer stopped: {:?}", res),
+            res = offerer_loop.fuse() => panic!("offerer stopped: {:?}", res),
+        }
+    });
+}
+
+#[test]
+fn recovery_under_loss() {
+    gst::init().unwrap();
+
+    task::block_on(async {
+        let server = start_server().await;
+
+        let (answerer, answerer_loop) = start_app!(&server, "1", &RECOVERY);
+        // Only what the offerer sends gets lost
+        let mut offerer_args = vec![
+            "--peer-id",
+            "1",
+            "--drop-probability",
+            "0.05",
+            "--impair",
+            "send",
+        ];
+        offerer_args.extend_from_slice(&RECOVERY);
+        let (offerer, offerer_loop) = start_app!(&server, "2", &offerer_args);
+
+        let recovered = future::timeout(DEADLINE, async {
+            while !has_media(&answerer) || !has_recovered(&answerer) {
+                task::sleep(Duration::from_millis(100)).await;
+            }
+        });
+
+        pin_mut!(answerer_loop, offerer_loop, recovered);
+        futures::select! {
+            res = recovered.fuse() => res.expect("no recovered packets before the deadline"),
+            res = answerer_loop.fuse() => panic!("answerer stopped: {:?}", res),
+            res = offerer_loop.fuse() => panic!("offerer stopped: {:?}", res),
+        }
+
+        let answer = offerer
+            .webrtcbin
+            .get_property("remote-description")
+            .unwrap()
+            .get::<gst_webrtc::WebRTCSessionDescription>()
+            .unwrap()
+            .unwrap();
+        offerer.args.recovery.verify_sdp(&answer.get_sdp()).unwrap();
+    });
+}
+
+// Request the statistics of the App's webrtcbin once
+async fn stats(app: &App) -> Vec<Stats> {
+    let (stats_tx, stats_rx) = oneshot::channel();
+    request_stats(&app.webrtcbin, move |stats| {
+        let _ = stats_tx.send(stats);
+    });
+
+    stats_rx.await.unwrap()
+}
+
+// Run both Apps over an impaired network for a while and print their statistics as one JSON line
+// each, for comparing codecs and FEC settings offline. Only run on request as it takes a while, e.g.
+// IMPAIRED_LOOPBACK_ARGS="--drop-probability 0.1 --video-codec vp9 --nack" \
+//     cargo test impaired_loopback -- --ignored --nocapture
+#[test]
+#[ignore]
+fn impaired_loopback() {
+    gst::init().unwrap();
+
+    let env_args = std::env::var("IMPAIRED_LOOPBACK_ARGS").ok();
+    let args = match env_args {
+        Some(ref env_args) => env_args.split_whitespace().collect::<Vec<_>>(),
+        None => IMPAIRMENT.to_vec(),
+    };
+
+    task::block_on(async {
+        let server = start_server().await;
+
+        let (answerer, answerer_loop) = start_app!(&server, "1", &args);
+        let mut offerer_args = vec!["--peer-id", "1"];
+        offerer_args.extend_from_slice(&args);
+        let (offerer, offerer_loop) = start_app!(&server, "2", &offerer_args);
+
+        let measurement = async {
+            future::timeout(DEADLINE, async {
+                while !has_media(&answerer) || !has_media(&offerer) {
+                    task::sleep(Duration::from_millis(100)).await;
+                }
+            })
+            .await
+            .expect("no media on both sides before the deadline");
+
+            task::sleep(MEASUREMENT).await;
+
+            for (name, app) in &[("answerer", &answerer), ("offerer", &offerer)] {
+                let stats = stats(app).await;
+                assert!(!stats.is_empty(), "no statistics from the {}", name);
+
+                let report = serde_json::json!({
+                    "app": name,
+                    "args": args,
+                    "stats": stats,
+                });
+                println!("{}", report);
+            }
+        };
+
+        pin_mut!(answerer_loop, offerer_loop, measurement);
+        futures::select! {
+            _ = measurement.fuse() => (),
+            res = answerer_loop.fuse() => panic!("answerer stopped: {:?}", res),
+            res = offerer_loop.fuse() => panic!("offerer stopped: {:?}", res),
+        }
+    });
+}
@@ -0,0 +1,131 @@
+// Async client for the signalling server
+
+use futures::future;
+use futures::sink::{Sink, SinkExt};
+use futures::stream::{Stream, StreamExt};
+
+use async_tungstenite::tungstenite;
+use tungstenite::Error as WsError;
+use tungstenite::Message as WsMessage;
+
+use anyhow::{anyhow, bail};
+
+use crate::protocol::{ClientMessage, ServerMessage};
+
+// Connection to the signalling server over any WebSocket transport
+#[derive(Debug)]
+pub struct Client<S> {
+    ws: S,
+}
+
+// Connect to the signalling server at the given URL
+pub async fn connect(
+    server: &str,
+) -> Result<
+    Client<impl Sink<WsMessage, Error = WsError> + Stream<Item = Result<WsMessage, WsError>> + Unpin>,
+    anyhow::Error,
+> {
+    let (ws, _) = async_tungstenite::async_std::connect_async(server).await?;
+
+    Ok(Client::new(ws))
+}
+
+impl<S> Client<S>
+where
+    S: Sink<WsMessage, Error = WsError> + Stream<Item = Result<WsMessage, WsError>> + Unpin,
+{
+    // Wrap an already connected WebSocket
+    pub fn new(ws: S) -> Self {
+        Client { ws }
+    }
+
+    // Send a single message to the server
+    pub async fn send(&mut self, msg: ClientMessage) -> Result<(), anyhow::Error> {
+        self.ws.send(WsMessage::Text(msg.to_string())).await?;
+
+        Ok(())
+    }
+
+    // Wait for the next message from the server, skipping WebSocket protocol messages
+    pub async fn recv(&mut self) -> Result<ServerMessage, anyhow::Error> {
+        loop {
+            let msg = self
+                .ws
+                .next()
+                .await
+                .ok_or_else(|| anyhow!("didn't receive anything"))??;
+
+            match msg {
+                WsMessage::Text(text) => return text.parse(),
+                WsMessage::Close(_) => bail!("server closed the connection"),
+                _ => (),
+            }
+        }
+    }
+
+    // Say HELLO to the server and see if it replies with HELLO
+    pub async fn register(&mut self, uid: &str) -> Result<(), anyhow::Error> {
+        self.send(ClientMessage::Hello {
+            uid: uid.to_string(),
+        })
+        .await?;
+
+        match self.recv().await? {
+            ServerMessage::Hello => Ok(()),
+            msg => bail!("server didn't say HELLO: {:?}", msg),
+        }
+    }
+
+    // Start a 1-1 session with the given peer
+    pub async fn start_session(&mut self, peer_id: &str) -> Result<(), anyhow::Error> {
+        self.send(ClientMessage::Session {
+            peer_id: peer_id.to_string(),
+        })
+        .await?;
+
+        match self.recv().await? {
+            ServerMessage::SessionOk => Ok(()),
+            msg => bail!("server error: {:?}", msg),
+        }
+    }
+
+    // Join the given room and return the ids of the peers already in it
+    pub async fn join_room(&mut self, room_id: &str) -> Result<Vec<String>, anyhow::Error> {
+        self.send(ClientMessage::Room {
+            room_id: room_id.to_string(),
+        })
+        .await?;
+
+        match self.recv().await? {
+            ServerMessage::RoomOk { peers } => Ok(peers),
+            msg => bail!("server error: {:?}", msg),
+        }
+    }
+
+    // Split the connection into a Sink for our messages and a Stream of the server's messages.
+    // The stream ends once the server closes the connection
+    pub fn split(
+        self,
+    ) -> (
+        impl Sink<ClientMessage, Error = anyhow::Error> + Unpin,
+        impl Stream<Item = Result<ServerMessage, anyhow::Error>> + Unpin,
+    ) {
+        let (ws_sink, ws_stream) = self.ws.split();
+
+        let sink = ws_sink.with(|msg: ClientMessage| {
+            future::ready(Ok::<_, anyhow::Error>(WsMessage::Text(msg.to_string())))
+        });
+
+        let stream = ws_stream
+            .take_while(|msg| future::ready(!matches!(msg, Ok(WsMessage::Close(_)))))
+            .filter_map(|msg| {
+                future::ready(match msg {
+                    Ok(WsMessage::Text(text)) => Some(text.parse::<ServerMessage>()),
+                    Ok(_) => None,
+                    Err(err) => Some(Err(err.into())),
+                })
+            });
+
+        (sink, stream)
+    }
+}
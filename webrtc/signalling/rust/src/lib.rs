@@ -0,0 +1,9 @@
+// Client and server side of the signalling protocol used by all the demos, see ../Protocol.md
+
+mod client;
+mod protocol;
+mod server;
+
+pub use client::{connect, Client};
+pub use protocol::{ClientMessage, PeerMessage, ServerMessage};
+pub use server::{tls_acceptor, Server, ServerOptions};
@@ -0,0 +1,55 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use structopt::StructOpt;
+
+use async_std::task;
+
+use webrtc_signalling::{Server, ServerOptions};
+
+#[derive(Debug, StructOpt)]
+struct Args {
+    #[structopt(long, default_value = "0.0.0.0")]
+    addr: String,
+    #[structopt(long, default_value = "8443")]
+    port: u16,
+    #[structopt(long, default_value = "30")]
+    keepalive_timeout: u64,
+    #[structopt(long, default_value = ".", parse(from_os_str))]
+    cert_path: PathBuf,
+    #[structopt(long)]
+    disable_ssl: bool,
+    #[structopt(long, default_value = "/health")]
+    health: String,
+}
+
+async fn async_main() -> Result<(), anyhow::Error> {
+    let args = Args::from_args();
+
+    let tls_acceptor = if args.disable_ssl {
+        None
+    } else {
+        println!("Using TLS with keys in {}", args.cert_path.display());
+        Some(webrtc_signalling::tls_acceptor(&args.cert_path)?)
+    };
+
+    let options = ServerOptions {
+        tls_acceptor,
+        health_path: Some(args.health).filter(|health| !health.is_empty()),
+        keepalive_timeout: Duration::from_secs(args.keepalive_timeout),
+    };
+
+    let server = Server::bind((args.addr.as_str(), args.port), options).await?;
+
+    println!(
+        "Listening on {}://{}",
+        if args.disable_ssl { "http" } else { "https" },
+        server.local_addr()?
+    );
+
+    server.run().await
+}
+
+fn main() -> Result<(), anyhow::Error> {
+    task::block_on(async_main())
+}
@@ -0,0 +1,448 @@
+// Typed representation of the signalling protocol described in Protocol.md
+//
+// Messages are plain text WebSocket frames. Which messages are valid depends on the direction,
+// so there is one type for the messages sent by the clients and one for the messages sent by
+// the server.
+
+use std::fmt;
+use std::str::FromStr;
+
+use serde_derive::{Deserialize, Serialize};
+
+use anyhow::{anyhow, bail};
+
+// Messages sent from a client to the signalling server
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClientMessage {
+    // `HELLO <uid>`: register with the server
+    Hello { uid: String },
+    // `SESSION <uid>`: start a 1-1 call with the given peer
+    Session { peer_id: String },
+    // `ROOM <room_id>`: join or create the given room
+    Room { room_id: String },
+    // `ROOM_PEER_MSG <peer_id> <msg>`: send a message to a peer in our room
+    RoomPeerMsg { peer_id: String, msg: String },
+    // `ROOM_PEER_LIST`: request the list of peers in our room
+    RoomPeerList,
+    // Any other message, forwarded verbatim to the peer while in a session
+    PeerMsg(String),
+}
+
+// Messages sent from the signalling server to a client
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ServerMessage {
+    // `HELLO`: registration was successful
+    Hello,
+    // `SESSION_OK`: the session with the requested peer was set up
+    SessionOk,
+    // `ROOM_OK <peer1_id> ...`: joined the room, with the peers that are already in it
+    RoomOk { peers: Vec<String> },
+    // `ROOM_PEER_MSG <peer_id> <msg>`: message from a peer in our room
+    RoomPeerMsg { peer_id: String, msg: String },
+    // `ROOM_PEER_JOINED <peer_id>`: a new peer joined our room
+    RoomPeerJoined { peer_id: String },
+    // `ROOM_PEER_LEFT <peer_id>`: a peer left our room
+    RoomPeerLeft { peer_id: String },
+    // `ROOM_PEER_LIST <peer1_id> ...`: all other peers currently in our room
+    RoomPeerList { peers: Vec<String> },
+    // `ERROR <reason>`: the last request failed
+    Error(String),
+    // Any other message, forwarded verbatim from the peer while in a session
+    PeerMsg(String),
+}
+
+// JSON messages the peers exchange for negotiating the call
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PeerMessage {
+    Ice {
+        candidate: String,
+        #[serde(rename = "sdpMLineIndex")]
+        sdp_mline_index: u32,
+    },
+    Sdp {
+        #[serde(rename = "type")]
+        type_: String,
+        sdp: String,
+    },
+    // Sent instead of negotiating to a room peer that isn't admitted into the call
+    Rejected {
+        reason: String,
+    },
+    // Sent to a room peer that waits for a free slot in the call, negotiating starts once it's
+    // admitted
+    Queued {
+        reason: String,
+        position: usize,
+    },
+    // Sent to a queued room peer once it's admitted into the call. An offer it sent while waiting
+    // was dropped, so it has to send a new one
+    Admitted {},
+}
+
+// Split a message into the command and the remainder after the first space
+fn split_command(msg: &str) -> (&str, &str) {
+    let mut split = msg.splitn(2, ' ');
+    let command = split.next().unwrap_or("");
+    let args = split.next().unwrap_or("");
+
+    (command, args)
+}
+
+// Parse a single id argument, which must not be empty or contain whitespace
+fn parse_id<'a>(command: &str, args: &'a str) -> Result<&'a str, anyhow::Error> {
+    let id = args.trim();
+    if id.is_empty() || id.split_whitespace().count() != 1 {
+        bail!("Invalid id {:?} in {} message", args, command);
+    }
+
+    Ok(id)
+}
+
+// Parse a whitespace separated list of peer ids, which might be empty
+fn parse_peer_list(args: &str) -> Vec<String> {
+    args.split_whitespace().map(String::from).collect()
+}
+
+// Parse the `<peer_id> <msg>` arguments of ROOM_PEER_MSG
+fn parse_room_peer_msg(args: &str) -> Result<(String, String), anyhow::Error> {
+    let (peer_id, msg) = split_command(args);
+    if peer_id.is_empty() {
+        bail!("Can't parse peer id in ROOM_PEER_MSG message");
+    }
+    if msg.is_empty() {
+        bail!("Can't parse peer message in ROOM_PEER_MSG message");
+    }
+
+    Ok((peer_id.to_string(), msg.to_string()))
+}
+
+impl FromStr for ClientMessage {
+    type Err = anyhow::Error;
+
+    fn from_str(msg: &str) -> Result<Self, Self::Err> {
+        let (command, args) = split_command(msg);
+
+        match command {
+            "HELLO" => Ok(ClientMessage::Hello {
+                uid: parse_id(command, args)?.to_string(),
+            }),
+            "SESSION" => Ok(ClientMessage::Session {
+                peer_id: parse_id(command, args)?.to_string(),
+            }),
+            "ROOM" => Ok(ClientMessage::Room {
+                room_id: parse_id(command, args)?.to_string(),
+            }),
+            "ROOM_PEER_MSG" => {
+                let (peer_id, msg) = parse_room_peer_msg(args)?;
+                Ok(ClientMessage::RoomPeerMsg { peer_id, msg })
+            }
+            "ROOM_PEER_LIST" if args.trim().is_empty() => Ok(ClientMessage::RoomPeerList),
+            _ => Ok(ClientMessage::PeerMsg(msg.to_string())),
+        }
+    }
+}
+
+impl FromStr for ServerMessage {
+    type Err = anyhow::Error;
+
+    fn from_str(msg: &str) -> Result<Self, Self::Err> {
+        let (command, args) = split_command(msg);
+
+        match command {
+            "HELLO" if args.is_empty() => Ok(ServerMessage::Hello),
+            "SESSION_OK" if args.is_empty() => Ok(ServerMessage::SessionOk),
+            "ROOM_OK" => Ok(ServerMessage::RoomOk {
+                peers: parse_peer_list(args),
+            }),
+            "ROOM_PEER_MSG" => {
+                let (peer_id, msg) = parse_room_peer_msg(args)?;
+                Ok(ServerMessage::RoomPeerMsg { peer_id, msg })
+            }
+            "ROOM_PEER_JOINED" => Ok(ServerMessage::RoomPeerJoined {
+                peer_id: parse_id(command, args)?.to_string(),
+            }),
+            "ROOM_PEER_LEFT" => Ok(ServerMessage::RoomPeerLeft {
+                peer_id: parse_id(command, args)?.to_string(),
+            }),
+            "ROOM_PEER_LIST" => Ok(ServerMessage::RoomPeerList {
+                peers: parse_peer_list(args),
+            }),
+            "ERROR" => Ok(ServerMessage::Error(args.to_string())),
+            "HELLO" | "SESSION_OK" => Err(anyhow!("Unexpected arguments in message {:?}", msg)),
+            _ => Ok(ServerMessage::PeerMsg(msg.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for ClientMessage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ClientMessage::Hello { uid } => write!(f, "HELLO {}", uid),
+            ClientMessage::Session { peer_id } => write!(f, "SESSION {}", peer_id),
+            ClientMessage::Room { room_id } => write!(f, "ROOM {}", room_id),
+            ClientMessage::RoomPeerMsg { peer_id, msg } => {
+                write!(f, "ROOM_PEER_MSG {} {}", peer_id, msg)
+            }
+            ClientMessage::RoomPeerList => write!(f, "ROOM_PEER_LIST"),
+            ClientMessage::PeerMsg(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl fmt::Display for ServerMessage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ServerMessage::Hello => write!(f, "HELLO"),
+            ServerMessage::SessionOk => write!(f, "SESSION_OK"),
+            ServerMessage::RoomOk { peers } => write!(f, "ROOM_OK {}", peers.join(" ")),
+            ServerMessage::RoomPeerMsg { peer_id, msg } => {
+                write!(f, "ROOM_PEER_MSG {} {}", peer_id, msg)
+            }
+            ServerMessage::RoomPeerJoined { peer_id } => write!(f, "ROOM_PEER_JOINED {}", peer_id),
+            ServerMessage::RoomPeerLeft { peer_id } => write!(f, "ROOM_PEER_LEFT {}", peer_id),
+            ServerMessage::RoomPeerList { peers } => {
+                write!(f, "ROOM_PEER_LIST {}", peers.join(" "))
+            }
+            ServerMessage::Error(reason) => write!(f, "ERROR {}", reason),
+            ServerMessage::PeerMsg(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peers(ids: &[&str]) -> Vec<String> {
+        ids.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn parse_client_messages() {
+        assert_eq!(
+            "HELLO 1234".parse::<ClientMessage>().unwrap(),
+            ClientMessage::Hello {
+                uid: "1234".into()
+            }
+        );
+        assert_eq!(
+            "SESSION 42".parse::<ClientMessage>().unwrap(),
+            ClientMessage::Session {
+                peer_id: "42".into()
+            }
+        );
+        assert_eq!(
+            "ROOM my-room".parse::<ClientMessage>().unwrap(),
+            ClientMessage::Room {
+                room_id: "my-room".into()
+            }
+        );
+        assert_eq!(
+            "ROOM_PEER_MSG 42 {\"ice\": {}}"
+                .parse::<ClientMessage>()
+                .unwrap(),
+            ClientMessage::RoomPeerMsg {
+                peer_id: "42".into(),
+                msg: "{\"ice\": {}}".into()
+            }
+        );
+        assert_eq!(
+            "ROOM_PEER_LIST".parse::<ClientMessage>().unwrap(),
+            ClientMessage::RoomPeerList
+        );
+        assert_eq!(
+            "{\"sdp\": {}}".parse::<ClientMessage>().unwrap(),
+            ClientMessage::PeerMsg("{\"sdp\": {}}".into())
+        );
+    }
+
+    #[test]
+    fn parse_invalid_client_messages() {
+        assert!("HELLO".parse::<ClientMessage>().is_err());
+        assert!("HELLO a b".parse::<ClientMessage>().is_err());
+        assert!("SESSION ".parse::<ClientMessage>().is_err());
+        assert!("ROOM".parse::<ClientMessage>().is_err());
+        assert!("ROOM_PEER_MSG 42".parse::<ClientMessage>().is_err());
+        assert!("ROOM_PEER_MSG".parse::<ClientMessage>().is_err());
+    }
+
+    #[test]
+    fn parse_server_messages() {
+        assert_eq!(
+            "HELLO".parse::<ServerMessage>().unwrap(),
+            ServerMessage::Hello
+        );
+        assert_eq!(
+            "SESSION_OK".parse::<ServerMessage>().unwrap(),
+            ServerMessage::SessionOk
+        );
+        assert_eq!(
+            "ROOM_OK ".parse::<ServerMessage>().unwrap(),
+            ServerMessage::RoomOk { peers: vec![] }
+        );
+        assert_eq!(
+            "ROOM_OK".parse::<ServerMessage>().unwrap(),
+            ServerMessage::RoomOk { peers: vec![] }
+        );
+        assert_eq!(
+            "ROOM_OK 1 2 3".parse::<ServerMessage>().unwrap(),
+            ServerMessage::RoomOk {
+                peers: peers(&["1", "2", "3"])
+            }
+        );
+        assert_eq!(
+            "ROOM_PEER_MSG 7 {\"sdp\": {\"type\": \"offer\"}}"
+                .parse::<ServerMessage>()
+                .unwrap(),
+            ServerMessage::RoomPeerMsg {
+                peer_id: "7".into(),
+                msg: "{\"sdp\": {\"type\": \"offer\"}}".into()
+            }
+        );
+        assert_eq!(
+            "ROOM_PEER_JOINED 7".parse::<ServerMessage>().unwrap(),
+            ServerMessage::RoomPeerJoined {
+                peer_id: "7".into()
+            }
+        );
+        assert_eq!(
+            "ROOM_PEER_LEFT 7".parse::<ServerMessage>().unwrap(),
+            ServerMessage::RoomPeerLeft {
+                peer_id: "7".into()
+            }
+        );
+        assert_eq!(
+            "ROOM_PEER_LIST ".parse::<ServerMessage>().unwrap(),
+            ServerMessage::RoomPeerList { peers: vec![] }
+        );
+        assert_eq!(
+            "ROOM_PEER_LIST 5 6".parse::<ServerMessage>().unwrap(),
+            ServerMessage::RoomPeerList {
+                peers: peers(&["5", "6"])
+            }
+        );
+        assert_eq!(
+            "ERROR peer '42' not found".parse::<ServerMessage>().unwrap(),
+            ServerMessage::Error("peer '42' not found".into())
+        );
+        assert_eq!(
+            "{\"ice\": {}}".parse::<ServerMessage>().unwrap(),
+            ServerMessage::PeerMsg("{\"ice\": {}}".into())
+        );
+    }
+
+    #[test]
+    fn parse_invalid_server_messages() {
+        assert!("HELLO 1234".parse::<ServerMessage>().is_err());
+        assert!("SESSION_OK 1".parse::<ServerMessage>().is_err());
+        assert!("ROOM_PEER_JOINED".parse::<ServerMessage>().is_err());
+        assert!("ROOM_PEER_LEFT ".parse::<ServerMessage>().is_err());
+        assert!("ROOM_PEER_MSG 7".parse::<ServerMessage>().is_err());
+    }
+
+    #[test]
+    fn client_messages_roundtrip() {
+        let msgs = [
+            ClientMessage::Hello { uid: "1".into() },
+            ClientMessage::Session {
+                peer_id: "2".into(),
+            },
+            ClientMessage::Room {
+                room_id: "3".into(),
+            },
+            ClientMessage::RoomPeerMsg {
+                peer_id: "4".into(),
+                msg: "some message with spaces".into(),
+            },
+            ClientMessage::RoomPeerList,
+            ClientMessage::PeerMsg("{}".into()),
+        ];
+
+        for msg in msgs.iter() {
+            assert_eq!(&msg.to_string().parse::<ClientMessage>().unwrap(), msg);
+        }
+    }
+
+    #[test]
+    fn server_messages_roundtrip() {
+        let msgs = [
+            ServerMessage::Hello,
+            ServerMessage::SessionOk,
+            ServerMessage::RoomOk { peers: vec![] },
+            ServerMessage::RoomOk {
+                peers: peers(&["1", "2"]),
+            },
+            ServerMessage::RoomPeerMsg {
+                peer_id: "4".into(),
+                msg: "some message with spaces".into(),
+            },
+            ServerMessage::RoomPeerJoined {
+                peer_id: "5".into(),
+            },
+            ServerMessage::RoomPeerLeft {
+                peer_id: "6".into(),
+            },
+            ServerMessage::RoomPeerList { peers: vec![] },
+            ServerMessage::RoomPeerList {
+                peers: peers(&["7"]),
+            },
+            ServerMessage::Error("invalid msg, already in room".into()),
+            ServerMessage::PeerMsg("{}".into()),
+        ];
+
+        for msg in msgs.iter() {
+            assert_eq!(&msg.to_string().parse::<ServerMessage>().unwrap(), msg);
+        }
+    }
+
+    #[test]
+    fn serialize_room_ok_like_simple_server() {
+        assert_eq!(
+            ServerMessage::RoomOk { peers: vec![] }.to_string(),
+            "ROOM_OK "
+        );
+        assert_eq!(
+            ServerMessage::RoomPeerList {
+                peers: peers(&["1", "2"])
+            }
+            .to_string(),
+            "ROOM_PEER_LIST 1 2"
+        );
+    }
+
+    #[test]
+    fn peer_messages() {
+        let msg: PeerMessage =
+            serde_json::from_str(r#"{"ice": {"candidate": "candidate:1", "sdpMLineIndex": 1}}"#)
+                .unwrap();
+        assert_eq!(
+            msg,
+            PeerMessage::Ice {
+                candidate: "candidate:1".into(),
+                sdp_mline_index: 1
+            }
+        );
+
+        let msg = PeerMessage::Sdp {
+            type_: "offer".into(),
+            sdp: "v=0".into(),
+        };
+        assert_eq!(
+            serde_json::to_string(&msg).unwrap(),
+            r#"{"sdp":{"type":"offer","sdp":"v=0"}}"#
+        );
+
+        let msg = PeerMessage::Queued {
+            reason: "room is full".into(),
+            position: 1,
+        };
+        assert_eq!(
+            serde_json::to_string(&msg).unwrap(),
+            r#"{"queued":{"reason":"room is full","position":1}}"#
+        );
+
+        let msg: PeerMessage = serde_json::from_str(r#"{"admitted": {}}"#).unwrap();
+        assert_eq!(msg, PeerMessage::Admitted {});
+    }
+}
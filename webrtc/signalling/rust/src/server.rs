@@ -0,0 +1,590 @@
+// Signalling server implementing Protocol.md, equivalent to simple_server.py
+
+use std::collections::{BTreeSet, HashMap};
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::Poll;
+use std::time::Duration;
+
+use async_std::future;
+use async_std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use async_std::task;
+use futures::channel::mpsc;
+use futures::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use futures::sink::SinkExt;
+use futures::stream::StreamExt;
+
+use async_native_tls::TlsAcceptor;
+
+use async_tungstenite::tungstenite;
+use tungstenite::protocol::frame::coding::CloseCode;
+use tungstenite::protocol::CloseFrame;
+use tungstenite::Message as WsMessage;
+
+use anyhow::{bail, Context};
+
+use crate::protocol::{ClientMessage, ServerMessage};
+
+// Maximum size of the HTTP request head we look at for the health check
+const MAX_REQUEST_HEAD_SIZE: usize = 8192;
+
+// Configuration of the signalling server
+pub struct ServerOptions {
+    // Accept TLS connections with this acceptor, or plain connections if unset
+    pub tls_acceptor: Option<TlsAcceptor>,
+    // Path on which plain HTTP requests are answered with "OK" for health checks
+    pub health_path: Option<String>,
+    // Send a ping to peers that were silent for this long
+    pub keepalive_timeout: Duration,
+}
+
+impl Default for ServerOptions {
+    fn default() -> Self {
+        ServerOptions {
+            tls_acceptor: None,
+            health_path: Some(String::from("/health")),
+            keepalive_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+// Signalling server listening on a TCP socket
+pub struct Server {
+    listener: TcpListener,
+    inner: Arc<ServerInner>,
+}
+
+// State shared between all connections
+struct ServerInner {
+    options: ServerOptions,
+    state: Mutex<State>,
+}
+
+// What a registered peer is currently doing
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum PeerStatus {
+    Idle,
+    // In a 1-1 session with the given peer
+    Session(String),
+    // In the given room
+    Room(String),
+}
+
+// One registered peer
+#[derive(Debug)]
+struct PeerState {
+    // Unique id of the connection, to not remove a peer that re-registered with the same uid
+    conn_id: u64,
+    // Channel to the task writing to the peer's WebSocket
+    tx: mpsc::UnboundedSender<WsMessage>,
+    status: PeerStatus,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    next_conn_id: u64,
+    peers: HashMap<String, PeerState>,
+    rooms: HashMap<String, BTreeSet<String>>,
+}
+
+// Create a TLS acceptor from the certificates in the given directory, looking for the same files
+// as simple_server.py
+pub fn tls_acceptor(cert_path: &Path) -> Result<TlsAcceptor, anyhow::Error> {
+    let (chain_pem, key_pem) = if cert_path.to_string_lossy().contains("letsencrypt") {
+        (
+            cert_path.join("fullchain.pem"),
+            cert_path.join("privkey.pem"),
+        )
+    } else {
+        (cert_path.join("cert.pem"), cert_path.join("key.pem"))
+    };
+
+    let chain = std::fs::read(&chain_pem).with_context(|| {
+        format!(
+            "Certificate {} not found, did you run generate_cert.sh?",
+            chain_pem.display()
+        )
+    })?;
+    let key = std::fs::read(&key_pem).with_context(|| {
+        format!(
+            "Key {} not found, did you run generate_cert.sh?",
+            key_pem.display()
+        )
+    })?;
+
+    let identity = native_tls::Identity::from_pkcs8(&chain, &key)?;
+    let acceptor = native_tls::TlsAcceptor::new(identity)?;
+
+    Ok(acceptor.into())
+}
+
+impl Server {
+    // Start listening on the given address
+    pub async fn bind(
+        addr: impl ToSocketAddrs,
+        options: ServerOptions,
+    ) -> Result<Self, anyhow::Error> {
+        let listener = TcpListener::bind(addr).await?;
+
+        Ok(Server {
+            listener,
+            inner: Arc::new(ServerInner {
+                options,
+                state: Mutex::new(State::default()),
+            }),
+        })
+    }
+
+    // The address we're listening on, e.g. to find out the port when binding to port 0
+    pub fn local_addr(&self) -> Result<SocketAddr, anyhow::Error> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    // Accept connections until the listener fails, handling each of them in a new task
+    pub async fn run(self) -> Result<(), anyhow::Error> {
+        let mut incoming = self.listener.incoming();
+
+        while let Some(stream) = incoming.next().await {
+            let stream = stream?;
+            let inner = self.inner.clone();
+
+            task::spawn(async move {
+                let raddr = stream
+                    .peer_addr()
+                    .map(|addr| addr.to_string())
+                    .unwrap_or_else(|_| String::from("unknown"));
+
+                if let Err(err) = inner.accept(stream, &raddr).await {
+                    println!("Connection to {} failed: {:?}", raddr, err);
+                }
+            });
+        }
+
+        Ok(())
+    }
+}
+
+impl ServerInner {
+    // Do the TLS handshake if needed and then handle the connection
+    async fn accept(&self, stream: TcpStream, raddr: &str) -> Result<(), anyhow::Error> {
+        match self.options.tls_acceptor {
+            Some(ref acceptor) => {
+                let stream = acceptor.accept(stream).await?;
+                self.handle_connection(stream, raddr).await
+            }
+            None => self.handle_connection(stream, raddr).await,
+        }
+    }
+
+    // Answer health checks and otherwise run the WebSocket connection of one peer
+    async fn handle_connection<S>(&self, stream: S, raddr: &str) -> Result<(), anyhow::Error>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let stream = match self.check_health(stream).await? {
+            Some(stream) => stream,
+            None => return Ok(()),
+        };
+
+        let ws = async_tungstenite::accept_async(stream).await?;
+        println!("Connected to {}", raddr);
+
+        let (mut ws_sink, mut ws_stream) = ws.split();
+
+        // Exchange HELLO and register the peer
+        let uid = match ws_stream.next().await {
+            Some(Ok(WsMessage::Text(text))) => match text.parse::<ClientMessage>() {
+                Ok(ClientMessage::Hello { uid }) => uid,
+                // A HELLO with an empty uid or one containing whitespace
+                Err(_) if text.splitn(2, ' ').next() == Some("HELLO") => {
+                    let _ = ws_sink
+                        .send(close_message(CloseCode::Protocol, "invalid peer uid"))
+                        .await;
+                    bail!("Invalid uid in {:?} from {}", text, raddr);
+                }
+                _ => {
+                    let _ = ws_sink
+                        .send(close_message(CloseCode::Protocol, "invalid protocol"))
+                        .await;
+                    bail!("Invalid hello from {}", raddr);
+                }
+            },
+            _ => bail!("Invalid hello from {}", raddr),
+        };
+
+        let (tx, mut rx) = mpsc::unbounded::<WsMessage>();
+        let conn_id = self.state.lock().unwrap().register(&uid, tx.clone());
+        let conn_id = match conn_id {
+            Some(conn_id) => conn_id,
+            None => {
+                let _ = ws_sink
+                    .send(close_message(CloseCode::Protocol, "invalid peer uid"))
+                    .await;
+                bail!("Invalid uid {} from {}", uid, raddr);
+            }
+        };
+        println!("Registered peer {} at {}", uid, raddr);
+
+        // Everything we send to the peer goes through the channel from now on
+        let writer = async move {
+            while let Some(msg) = rx.next().await {
+                let close = matches!(msg, WsMessage::Close(_));
+                if ws_sink.send(msg).await.is_err() || close {
+                    break;
+                }
+            }
+        };
+
+        let reader = async {
+            let _ = tx.unbounded_send(WsMessage::Text(ServerMessage::Hello.to_string()));
+
+            loop {
+                // Wait for a message forever, and send a regular ping to prevent bad routers
+                // from closing the connection
+                let msg =
+                    match future::timeout(self.options.keepalive_timeout, ws_stream.next()).await {
+                        Err(_) => {
+                            println!("Sending keepalive ping to {} in recv", raddr);
+                            let _ = tx.unbounded_send(WsMessage::Ping(Vec::new()));
+                            continue;
+                        }
+                        Ok(Some(Ok(msg))) => msg,
+                        Ok(Some(Err(err))) => {
+                            println!("Connection to peer {} failed: {}", uid, err);
+                            break;
+                        }
+                        Ok(None) => break,
+                    };
+
+                match msg {
+                    WsMessage::Text(text) => {
+                        self.state.lock().unwrap().handle_message(&uid, &text);
+                    }
+                    WsMessage::Close(_) => break,
+                    _ => (),
+                }
+            }
+
+            println!("Connection to peer {} closed, exiting handler", uid);
+            self.state.lock().unwrap().remove_peer(&uid, conn_id);
+            drop(tx);
+        };
+
+        futures::join!(reader, writer);
+
+        Ok(())
+    }
+
+    // Read the HTTP request head and answer it directly if it is for the health check path.
+    // Otherwise return a stream that replays the request head for the WebSocket handshake
+    async fn check_health<S>(&self, mut stream: S) -> Result<Option<Prefixed<S>>, anyhow::Error>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let health_path = match self.options.health_path {
+            Some(ref health_path) => health_path,
+            None => return Ok(Some(Prefixed::new(Vec::new(), stream))),
+        };
+
+        let mut head = Vec::new();
+        let mut buf = [0u8; 1024];
+        while !head.windows(4).any(|w| w == b"\r\n\r\n") && head.len() < MAX_REQUEST_HEAD_SIZE {
+            let n = stream.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            head.extend_from_slice(&buf[..n]);
+        }
+
+        // GET <path> HTTP/1.1
+        let path = head
+            .split(|b| *b == b'\n')
+            .next()
+            .and_then(|line| std::str::from_utf8(line).ok())
+            .and_then(|line| line.split_whitespace().nth(1));
+
+        if path == Some(health_path.as_str()) {
+            stream
+                .write_all(
+                    b"HTTP/1.1 200 OK\r\n\
+                      Content-Type: text/plain\r\n\
+                      Content-Length: 3\r\n\
+                      Connection: close\r\n\
+                      \r\n\
+                      OK\n",
+                )
+                .await?;
+            stream.close().await?;
+
+            return Ok(None);
+        }
+
+        Ok(Some(Prefixed::new(head, stream)))
+    }
+}
+
+impl State {
+    // Register a new peer, or return None if the uid is already taken
+    fn register(&mut self, uid: &str, tx: mpsc::UnboundedSender<WsMessage>) -> Option<u64> {
+        if self.peers.contains_key(uid) {
+            return None;
+        }
+
+        let conn_id = self.next_conn_id;
+        self.next_conn_id += 1;
+
+        self.peers.insert(
+            uid.to_string(),
+            PeerState {
+                conn_id,
+                tx,
+                status: PeerStatus::Idle,
+            },
+        );
+
+        Some(conn_id)
+    }
+
+    // Queue a message for sending to the given peer, if it is still connected
+    fn send(&self, uid: &str, msg: ServerMessage) {
+        if let Some(peer) = self.peers.get(uid) {
+            let _ = peer.tx.unbounded_send(WsMessage::Text(msg.to_string()));
+        }
+    }
+
+    fn send_error(&self, uid: &str, reason: String) {
+        self.send(uid, ServerMessage::Error(reason));
+    }
+
+    // Handle a text message from the given peer depending on its current status
+    fn handle_message(&mut self, uid: &str, text: &str) {
+        let status = match self.peers.get(uid) {
+            Some(peer) => peer.status.clone(),
+            None => return,
+        };
+
+        match status {
+            // We're in a session, route message to connected peer
+            PeerStatus::Session(other_id) => {
+                println!("{} -> {}: {}", uid, other_id, text);
+                self.send(&other_id, ServerMessage::PeerMsg(text.to_string()));
+            }
+            PeerStatus::Room(room_id) => self.handle_room_message(uid, &room_id, text),
+            PeerStatus::Idle => self.handle_command(uid, text),
+        }
+    }
+
+    // Handle the room-specific commands of a peer in a room
+    fn handle_room_message(&mut self, uid: &str, room_id: &str, text: &str) {
+        match text.parse::<ClientMessage>() {
+            Ok(ClientMessage::RoomPeerMsg { peer_id, msg }) => {
+                let other_status = match self.peers.get(&peer_id) {
+                    Some(other) => &other.status,
+                    None => {
+                        self.send_error(uid, format!("peer '{}' not found", peer_id));
+                        return;
+                    }
+                };
+
+                if *other_status != PeerStatus::Room(room_id.to_string()) {
+                    self.send_error(uid, format!("peer '{}' is not in the room", peer_id));
+                    return;
+                }
+
+                let msg = ServerMessage::RoomPeerMsg {
+                    peer_id: uid.to_string(),
+                    msg,
+                };
+                println!("room {}: {} -> {}: {}", room_id, uid, peer_id, msg);
+                self.send(&peer_id, msg);
+            }
+            Ok(ClientMessage::RoomPeerList) => {
+                let peers = self
+                    .rooms
+                    .get(room_id)
+                    .map(|peers| peers.iter().filter(|pid| *pid != uid).cloned().collect())
+                    .unwrap_or_default();
+
+                let msg = ServerMessage::RoomPeerList { peers };
+                println!("room {}: -> {}: {}", room_id, uid, msg);
+                self.send(uid, msg);
+            }
+            _ => self.send_error(uid, String::from("invalid msg, already in room")),
+        }
+    }
+
+    // Handle the commands of a peer that is neither in a session nor in a room
+    fn handle_command(&mut self, uid: &str, text: &str) {
+        match text.parse::<ClientMessage>() {
+            // Requested a session with a specific peer
+            Ok(ClientMessage::Session { peer_id }) => {
+                println!("{} command {}", uid, text);
+
+                let callee_status = match self.peers.get(&peer_id) {
+                    Some(callee) if peer_id != uid => &callee.status,
+                    _ => {
+                        self.send_error(uid, format!("peer '{}' not found", peer_id));
+                        return;
+                    }
+                };
+
+                if *callee_status != PeerStatus::Idle {
+                    self.send_error(uid, format!("peer '{}' busy", peer_id));
+                    return;
+                }
+
+                self.send(uid, ServerMessage::SessionOk);
+                println!("Session from {} to {}", uid, peer_id);
+
+                // Register session
+                self.peers.get_mut(uid).unwrap().status = PeerStatus::Session(peer_id.clone());
+                self.peers.get_mut(&peer_id).unwrap().status = PeerStatus::Session(uid.to_string());
+            }
+            // Requested joining or creation of a room
+            Ok(ClientMessage::Room { room_id }) => {
+                println!("{} command {}", uid, text);
+
+                // Room name cannot be 'session'
+                if room_id == "session" {
+                    self.send_error(uid, format!("invalid room id '{}'", room_id));
+                    return;
+                }
+
+                let room_peers = self.rooms.entry(room_id.clone()).or_default();
+                let peers = room_peers.iter().cloned().collect::<Vec<_>>();
+                room_peers.insert(uid.to_string());
+
+                self.send(
+                    uid,
+                    ServerMessage::RoomOk {
+                        peers: peers.clone(),
+                    },
+                );
+
+                // Enter room
+                self.peers.get_mut(uid).unwrap().status = PeerStatus::Room(room_id.clone());
+
+                for pid in peers {
+                    let msg = ServerMessage::RoomPeerJoined {
+                        peer_id: uid.to_string(),
+                    };
+                    println!("room {}: {} -> {}: {}", room_id, uid, pid, msg);
+                    self.send(&pid, msg);
+                }
+            }
+            _ => println!("Ignoring unknown message {:?} from {}", text, uid),
+        }
+    }
+
+    // Remove a disconnected peer and clean up its session or room
+    fn remove_peer(&mut self, uid: &str, conn_id: u64) {
+        match self.peers.get(uid) {
+            Some(peer) if peer.conn_id == conn_id => (),
+            _ => return,
+        }
+
+        let peer = self.peers.remove(uid).unwrap();
+
+        match peer.status {
+            PeerStatus::Session(other_id) => {
+                // If there was a session with this peer, also close the connection to reset its
+                // state
+                if let Some(other) = self.peers.remove(&other_id) {
+                    println!("Closing connection to {}", other_id);
+                    let _ = other
+                        .tx
+                        .unbounded_send(close_message(CloseCode::Normal, ""));
+                }
+            }
+            PeerStatus::Room(room_id) => {
+                let room_peers = match self.rooms.get_mut(&room_id) {
+                    Some(room_peers) => room_peers,
+                    None => return,
+                };
+
+                room_peers.remove(uid);
+                let room_peers = room_peers.iter().cloned().collect::<Vec<_>>();
+                if room_peers.is_empty() {
+                    self.rooms.remove(&room_id);
+                }
+
+                for pid in room_peers {
+                    let msg = ServerMessage::RoomPeerLeft {
+                        peer_id: uid.to_string(),
+                    };
+                    println!("room {}: {} -> {}: {}", room_id, uid, pid, msg);
+                    self.send(&pid, msg);
+                }
+            }
+            PeerStatus::Idle => (),
+        }
+
+        println!("Disconnected from peer {}", uid);
+    }
+}
+
+fn close_message(code: CloseCode, reason: &'static str) -> WsMessage {
+    WsMessage::Close(Some(CloseFrame {
+        code,
+        reason: reason.into(),
+    }))
+}
+
+// Stream that first returns the already read prefix and then continues reading from the inner
+// stream
+struct Prefixed<S> {
+    prefix: Vec<u8>,
+    pos: usize,
+    inner: S,
+}
+
+impl<S> Prefixed<S> {
+    fn new(prefix: Vec<u8>, inner: S) -> Self {
+        Prefixed {
+            prefix,
+            pos: 0,
+            inner,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Prefixed<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        if this.pos < this.prefix.len() {
+            let n = std::cmp::min(buf.len(), this.prefix.len() - this.pos);
+            buf[..n].copy_from_slice(&this.prefix[this.pos..this.pos + n]);
+            this.pos += n;
+
+            return Poll::Ready(Ok(n));
+        }
+
+        Pin::new(&mut this.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Prefixed<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut std::task::Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut std::task::Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_close(cx)
+    }
+}
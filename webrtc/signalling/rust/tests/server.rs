@@ -0,0 +1,195 @@
+use std::time::Duration;
+
+use async_std::future;
+use async_std::net::TcpStream;
+use async_std::prelude::*;
+use async_std::task;
+use futures::sink::SinkExt;
+
+use async_tungstenite::tungstenite::Message as WsMessage;
+
+use webrtc_signalling::{ClientMessage, Server, ServerMessage, ServerOptions};
+
+// Start a plain server on a random local port and return its URL
+async fn start_server() -> String {
+    let server = Server::bind("127.0.0.1:0", ServerOptions::default())
+        .await
+        .unwrap();
+    let addr = server.local_addr().unwrap();
+    task::spawn(server.run());
+
+    format!("ws://{}", addr)
+}
+
+// Connect to the server and register with the given uid
+macro_rules! connect {
+    ($server:expr, $uid:expr) => {{
+        let mut client = webrtc_signalling::connect($server).await.unwrap();
+        client.register($uid).await.unwrap();
+
+        client
+    }};
+}
+
+macro_rules! recv {
+    ($client:expr) => {
+        future::timeout(Duration::from_secs(5), $client.recv())
+            .await
+            .expect("timed out")
+            .unwrap()
+    };
+}
+
+#[test]
+fn session() {
+    task::block_on(async {
+        let server = start_server().await;
+
+        let mut callee = connect!(&server, "1");
+        let mut caller = connect!(&server, "2");
+        caller.start_session("1").await.unwrap();
+
+        caller
+            .send(ClientMessage::PeerMsg("{\"sdp\": {}}".into()))
+            .await
+            .unwrap();
+        assert_eq!(
+            recv!(callee),
+            ServerMessage::PeerMsg("{\"sdp\": {}}".into())
+        );
+
+        callee
+            .send(ClientMessage::PeerMsg("{\"ice\": {}}".into()))
+            .await
+            .unwrap();
+        assert_eq!(
+            recv!(caller),
+            ServerMessage::PeerMsg("{\"ice\": {}}".into())
+        );
+    });
+}
+
+#[test]
+fn session_errors() {
+    task::block_on(async {
+        let server = start_server().await;
+
+        let _callee = connect!(&server, "1");
+        let mut caller = connect!(&server, "2");
+        assert!(caller.start_session("3").await.is_err());
+
+        let mut other = connect!(&server, "4");
+        caller.start_session("1").await.unwrap();
+        assert!(other.start_session("1").await.is_err());
+
+        // Each uid can only be registered once
+        let mut duplicate = webrtc_signalling::connect(&server).await.unwrap();
+        assert!(duplicate.register("1").await.is_err());
+    });
+}
+
+#[test]
+fn invalid_hello() {
+    task::block_on(async {
+        let server = start_server().await;
+
+        // Closed with the same reasons as by simple_server.py
+        for (hello, reason) in &[
+            ("HELLO a b", "invalid peer uid"),
+            ("SESSION 1", "invalid protocol"),
+        ] {
+            let (mut ws, _) = async_tungstenite::async_std::connect_async(&server)
+                .await
+                .unwrap();
+            ws.send(WsMessage::Text(hello.to_string())).await.unwrap();
+
+            let msg = future::timeout(Duration::from_secs(5), ws.next())
+                .await
+                .expect("timed out")
+                .unwrap()
+                .unwrap();
+            match msg {
+                WsMessage::Close(Some(frame)) => assert_eq!(frame.reason, *reason),
+                msg => panic!("Unexpected message {:?}", msg),
+            }
+        }
+    });
+}
+
+#[test]
+fn room() {
+    task::block_on(async {
+        let server = start_server().await;
+
+        let mut first = connect!(&server, "1");
+        assert_eq!(first.join_room("room").await.unwrap(), Vec::<String>::new());
+
+        let mut second = connect!(&server, "2");
+        assert_eq!(second.join_room("room").await.unwrap(), vec!["1"]);
+        assert_eq!(
+            recv!(first),
+            ServerMessage::RoomPeerJoined {
+                peer_id: "2".into()
+            }
+        );
+
+        second
+            .send(ClientMessage::RoomPeerMsg {
+                peer_id: "1".into(),
+                msg: "{\"sdp\": {}}".into(),
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            recv!(first),
+            ServerMessage::RoomPeerMsg {
+                peer_id: "2".into(),
+                msg: "{\"sdp\": {}}".into()
+            }
+        );
+
+        second
+            .send(ClientMessage::RoomPeerMsg {
+                peer_id: "3".into(),
+                msg: "{}".into(),
+            })
+            .await
+            .unwrap();
+        assert!(matches!(recv!(second), ServerMessage::Error(_)));
+
+        first.send(ClientMessage::RoomPeerList).await.unwrap();
+        assert_eq!(
+            recv!(first),
+            ServerMessage::RoomPeerList {
+                peers: vec!["2".into()]
+            }
+        );
+
+        drop(second);
+        assert_eq!(
+            recv!(first),
+            ServerMessage::RoomPeerLeft {
+                peer_id: "2".into()
+            }
+        );
+    });
+}
+
+#[test]
+fn health_check() {
+    task::block_on(async {
+        let server = start_server().await;
+
+        let mut stream = TcpStream::connect(&server["ws://".len()..]).await.unwrap();
+        stream
+            .write_all(b"GET /health HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("\r\n\r\nOK\n"));
+    });
+}